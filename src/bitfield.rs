@@ -0,0 +1,118 @@
+use anyhow::bail;
+
+/// A set of piece indices, stored in the wire format of the `Bitfield` message.
+///
+/// The high bit of the first byte is piece 0, and any spare bits at the end are zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl Bitfield {
+    /// An empty bitfield for `len` pieces.
+    pub fn new(len: u32) -> Self {
+        Self {
+            bytes: vec![0; (len as usize).div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield for `len` pieces with every piece set.
+    pub fn full(len: u32) -> Self {
+        let mut ret = Self::new(len);
+        for index in 0..len {
+            ret.set(index);
+        }
+        ret
+    }
+
+    /// Parse a bitfield for `len` pieces, rejecting the wrong length or set spare bits.
+    pub fn from_bytes(bytes: Vec<u8>, len: u32) -> anyhow::Result<Self> {
+        let expected = (len as usize).div_ceil(8);
+        if bytes.len() != expected {
+            bail!(
+                "Bitfield has {} bytes, expected {} for {} pieces",
+                bytes.len(),
+                expected,
+                len
+            );
+        }
+        let spare = (expected * 8) as u32 - len;
+        if spare > 0 && bytes[expected - 1] & ((1 << spare) - 1) != 0 {
+            bail!("Bitfield has spare bits set");
+        }
+        Ok(Self { bytes, len })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        index < self.len && self.bytes[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Mark piece `index` as present, ignoring out of range indices.
+    pub fn set(&mut self, index: u32) {
+        if index < self.len {
+            self.bytes[index as usize / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Number of pieces present
+    pub fn count(&self) -> u32 {
+        self.bytes.iter().map(|b| b.count_ones()).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the pieces which are present
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter(|&index| self.has(index))
+    }
+
+    /// Indices of the pieces which are not present
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter(|&index| !self.has(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_read_high_bit_first() {
+        let bitfield = Bitfield::from_bytes(vec![0b1010_0000, 0b0100_0000], 10).unwrap();
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), [0, 2, 9]);
+        assert_eq!(bitfield.count(), 3);
+        assert!(!bitfield.has(10));
+        assert!(!bitfield.is_complete());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0], 10)
+            .unwrap()
+            .is_complete());
+        assert_eq!(Bitfield::full(10).as_bytes(), [0xff, 0xc0]);
+    }
+
+    #[test]
+    fn wrong_lengths_and_spare_bits_are_rejected() {
+        assert!(Bitfield::from_bytes(vec![0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0, 0], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xe0], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0x01], 10).is_err());
+        // no spare bits when the length is a multiple of 8
+        assert!(Bitfield::from_bytes(vec![0xff], 8).is_ok());
+        assert!(Bitfield::from_bytes(Vec::new(), 0).unwrap().is_empty());
+    }
+}
@@ -2,6 +2,8 @@ use std::{net::SocketAddr, path::PathBuf};
 
 use clap::{Parser, Subcommand};
 
+use crate::magnet::Magnet;
+
 #[derive(Debug, Clone, Parser)]
 pub struct Cli {
     #[clap(subcommand)]
@@ -13,9 +15,15 @@ pub struct Cli {
 pub enum SubCmd {
     Decode {
         string: String,
+        /// Reject input which isn't canonical bencode rather than warning about it
+        #[clap(long)]
+        strict: bool,
     },
     DecodeFile {
         torrent_file: PathBuf,
+        /// Reject input which isn't canonical bencode rather than warning about it
+        #[clap(long)]
+        strict: bool,
     },
     Info {
         torrent_file: PathBuf,
@@ -36,6 +44,84 @@ pub enum SubCmd {
     DownloadFile {
         #[clap(short)]
         out: PathBuf,
+        /// Path to a .torrent file or a magnet link
         torrent_file: PathBuf,
+        /// Keep uploading to peers once the download is complete
+        #[clap(long)]
+        seed: bool,
+        /// Port to accept connections from other peers on
+        #[clap(long, default_value_t = 6881)]
+        port: u16,
+        /// DHT node to bootstrap from as `host:port`, enabling peer discovery without a tracker.
+        /// May be given more than once
+        #[clap(long = "dht-node")]
+        dht_nodes: Vec<String>,
+    },
+    /// Run a DHT node until interrupted, for other nodes to bootstrap from
+    DhtNode {
+        /// UDP port to listen on
+        #[clap(long, default_value_t = 6881)]
+        port: u16,
+        /// DHT node to bootstrap from as `host:port`. May be given more than once
+        #[clap(long = "dht-node")]
+        dht_nodes: Vec<String>,
+    },
+    /// Create a .torrent file sharing a file or directory
+    Create {
+        #[clap(short)]
+        out: PathBuf,
+        /// The file or directory to share
+        path: PathBuf,
+        /// Tracker announce URL. Each use adds a tier, within which URLs may be separated by
+        /// commas
+        #[clap(long = "tracker")]
+        trackers: Vec<String>,
+        /// Bytes per piece, a power of two; picked from the total size if not given
+        #[clap(long = "piece-length")]
+        piece_length: Option<u32>,
+        #[clap(long)]
+        comment: Option<String>,
+        /// Only find peers through the trackers
+        #[clap(long)]
+        private: bool,
+        /// URL the files can also be downloaded from. May be given more than once
+        #[clap(long = "web-seed")]
+        web_seeds: Vec<String>,
+        /// Leave out the creation date
+        #[clap(long = "no-date")]
+        no_date: bool,
+    },
+    /// Check downloaded data against a torrent, piece by piece
+    Verify {
+        torrent_file: PathBuf,
+        /// The downloaded file, or the directory holding a multi-file torrent's files
+        path: PathBuf,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Ask trackers how many peers are sharing torrents
+    Scrape {
+        /// Tracker announce URL to ask instead of each torrent's own tracker
+        #[clap(long)]
+        tracker: Option<String>,
+        /// .torrent files, or info hashes as 40 hex digits when `--tracker` is given
+        #[clap(required = true)]
+        torrents: Vec<String>,
+    },
+    /// Run an HTTP tracker until interrupted
+    Tracker {
+        /// TCP port to serve `/announce` and `/scrape` on
+        #[clap(long, default_value_t = 8000)]
+        port: u16,
+        /// Seconds clients should wait between announces
+        #[clap(long, default_value_t = 1800)]
+        interval: u64,
+    },
+    MagnetParse {
+        link: Magnet,
+    },
+    MagnetInfo {
+        link: Magnet,
     },
 }
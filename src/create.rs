@@ -0,0 +1,308 @@
+//! Building .torrent files from files on disk.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    decode::{decode, Value},
+    peer::BLOCK_SIZE,
+    storage::FileEntry,
+};
+
+/// Piece lengths are picked to give roughly this many pieces
+const TARGET_PIECES: u64 = 1500;
+
+const MAX_PIECE_LENGTH: u64 = 16 << 20;
+
+/// What to put in a new torrent besides the files themselves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreateOptions {
+    /// Picked from the total size when not given
+    pub piece_length: Option<u32>,
+    /// Tiers of tracker URLs, where the first tracker also becomes `announce`
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Seconds since the Unix epoch
+    pub creation_date: Option<u64>,
+    /// Ask clients to only find peers through the trackers (BEP 27)
+    pub private: bool,
+    /// URLs the files can also be downloaded from over HTTP (BEP 19)
+    pub web_seeds: Vec<String>,
+}
+
+/// Create a torrent sharing the file or directory at `path`, returning its info hash and the
+/// encoded .torrent file.
+///
+/// The files of a directory are added in order of their paths, and pieces are hashed on as many
+/// threads as there are cores.
+pub async fn create(
+    path: impl AsRef<Path>,
+    options: &CreateOptions,
+) -> anyhow::Result<([u8; 20], Vec<u8>)> {
+    let path = path.as_ref().to_path_buf();
+    let options = options.clone();
+    tokio::task::spawn_blocking(move || create_blocking(&path, &options)).await?
+}
+
+fn create_blocking(path: &Path, options: &CreateOptions) -> anyhow::Result<([u8; 20], Vec<u8>)> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("opening {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("file name is not valid UTF-8")?
+        .to_string();
+
+    // files are paired with their path components relative to `path`
+    let mut files = Vec::new();
+    let single = path.is_file();
+    if single {
+        files.push((path.clone(), Vec::new()));
+    } else {
+        walk(&path, &mut Vec::new(), &mut files)?;
+    }
+    let mut entries = Vec::with_capacity(files.len());
+    let mut offset = 0;
+    for (file, _) in &files {
+        let length = file
+            .metadata()
+            .with_context(|| format!("reading {}", file.display()))?
+            .len();
+        entries.push(FileEntry {
+            path: file.clone(),
+            offset,
+            length,
+        });
+        offset += length;
+    }
+    let total = offset;
+    ensure!(total > 0, "{} holds no data to share", path.display());
+
+    let piece_length = match options.piece_length {
+        Some(length) => {
+            ensure!(
+                length.is_power_of_two() && length >= BLOCK_SIZE,
+                "piece length must be a power of two of at least {}",
+                BLOCK_SIZE
+            );
+            length
+        }
+        None => (total / TARGET_PIECES)
+            .next_power_of_two()
+            .clamp(BLOCK_SIZE.into(), MAX_PIECE_LENGTH) as u32,
+    };
+    let pieces = hash_pieces(&entries, piece_length, total)?;
+
+    let lengths: Vec<_> = entries.iter().map(|e| e.length as i64).collect();
+    let mut info = Value::dict()
+        .with("name", name)
+        .with("piece length", piece_length)
+        .with("pieces", pieces);
+    if single {
+        info.insert("length", lengths[0]);
+    } else {
+        let list = files
+            .iter()
+            .zip(&lengths)
+            .map(|((_, components), &length)| {
+                Value::dict()
+                    .with("length", length)
+                    .with("path", strings(components))
+            })
+            .collect();
+        info.insert("files", Value::List(list));
+    }
+    if options.private {
+        info.insert("private", 1);
+    }
+
+    let mut torrent = Value::dict().with("info", info);
+    if let Some(announce) = options.trackers.iter().flatten().next() {
+        torrent.insert("announce", announce.as_str());
+    }
+    // a single tracker needs no list
+    if options.trackers.iter().flatten().nth(1).is_some() {
+        let tiers = options
+            .trackers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| strings(tier))
+            .collect();
+        torrent.insert("announce-list", Value::List(tiers));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert("comment", comment.as_str());
+    }
+    if let Some(created_by) = &options.created_by {
+        torrent.insert("created by", created_by.as_str());
+    }
+    if let Some(date) = options.creation_date {
+        torrent.insert("creation date", date as i64);
+    }
+    if !options.web_seeds.is_empty() {
+        torrent.insert("url-list", strings(&options.web_seeds));
+    }
+
+    let mut buf = Vec::new();
+    torrent.encode(&mut buf)?;
+    // hash the info dictionary exactly as it was written
+    let (_, value) = decode(&buf).context("decoding new torrent")?;
+    let info = value
+        .get("info")
+        .and_then(|info| info.source)
+        .context("new torrent has no info dictionary")?;
+    let info_hash = Sha1::digest(info).into();
+    Ok((info_hash, buf))
+}
+
+/// Add every file under `dir` to `files` in order of their paths, where `prefix` holds the path
+/// components leading from the torrent's root to `dir`.
+///
+/// Symlinks to files are followed, but symlinked directories are skipped as they may lead back
+/// up the tree.
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, Vec<String>)>,
+) -> anyhow::Result<()> {
+    let mut children = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .map(|entry| entry.and_then(|e| Ok((e.path(), e.file_type()?))))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (child, file_type) in children {
+        if file_type.is_symlink() && child.is_dir() {
+            eprintln!("skipping symlinked directory {}", child.display());
+            continue;
+        }
+        let name = child
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} is not valid UTF-8", child.display()))?;
+        prefix.push(name.to_string());
+        if file_type.is_dir() {
+            walk(&child, prefix, files)?;
+        } else {
+            files.push((child.clone(), prefix.clone()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// The SHA-1 hashes of every piece of `files`, concatenated.
+fn hash_pieces(files: &[FileEntry], piece_length: u32, total: u64) -> anyhow::Result<Vec<u8>> {
+    let piece_length = u64::from(piece_length);
+    let piece_count = total.div_ceil(piece_length) as usize;
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(piece_count);
+    // each thread hashes a contiguous run of pieces, reading its own file handles
+    let per_thread = piece_count.div_ceil(threads);
+
+    let mut pieces = vec![0; piece_count * 20];
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = pieces
+            .chunks_mut(per_thread * 20)
+            .enumerate()
+            .map(|(thread, hashes)| {
+                scope.spawn(move || -> anyhow::Result<()> {
+                    let mut reader = Reader::new(files);
+                    let mut buf = vec![0; piece_length as usize];
+                    for (i, hash) in hashes.chunks_exact_mut(20).enumerate() {
+                        let start = (thread * per_thread + i) as u64 * piece_length;
+                        let piece = &mut buf[..std::cmp::min(piece_length, total - start) as usize];
+                        reader.read_at(start, piece)?;
+                        hash.copy_from_slice(&Sha1::digest(&*piece));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("hashing thread panicked"))
+    })?;
+    Ok(pieces)
+}
+
+/// Reads ranges of the concatenation of a torrent's files, opening each file when first needed.
+struct Reader<'a> {
+    files: &'a [FileEntry],
+    handles: Vec<Option<File>>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(files: &'a [FileEntry]) -> Self {
+        Self {
+            files,
+            handles: files.iter().map(|_| None).collect(),
+        }
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> anyhow::Result<()> {
+        while !buf.is_empty() {
+            let index = self
+                .files
+                .partition_point(|f| f.offset + f.length <= offset);
+            let entry = self.files.get(index).context("read past the last file")?;
+            let file = match &mut self.handles[index] {
+                Some(file) => file,
+                handle => handle.insert(
+                    File::open(&entry.path)
+                        .with_context(|| format!("opening {}", entry.path.display()))?,
+                ),
+            };
+            let len = std::cmp::min(buf.len() as u64, entry.offset + entry.length - offset);
+            let (chunk, rest) = buf.split_at_mut(len as usize);
+            file.seek(SeekFrom::Start(offset - entry.offset))?;
+            file.read_exact(chunk)
+                .with_context(|| format!("reading {}", entry.path.display()))?;
+            offset += len;
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+fn strings(list: &[String]) -> Value {
+    list.iter().map(|s| Value::from(s.as_str())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Torrent;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_directories_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("file"), b"data").unwrap();
+        std::fs::write(dir.path().join("outside"), b"more data").unwrap();
+        // a loop back up the tree, and a file from elsewhere
+        std::os::unix::fs::symlink("..", root.join("sub").join("up")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside"), root.join("link")).unwrap();
+
+        let (_, encoded) = create(&root, &CreateOptions::default()).await.unwrap();
+        let torrent_file = dir.path().join("shared.torrent");
+        std::fs::write(&torrent_file, encoded).unwrap();
+        let (_, torrent) = Torrent::read_file(&torrent_file).await.unwrap();
+        let crate::FileLayout::Multi { files } = &torrent.info.files else {
+            panic!("expected several files");
+        };
+        let paths: Vec<_> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["link", "sub/file"]);
+        assert_eq!(torrent.info.length(), 13);
+    }
+}
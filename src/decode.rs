@@ -1,17 +1,26 @@
-use std::{collections::HashMap, fmt::Display, io::Write, ops::Index};
-
-use anyhow::{bail, Context};
-use nom::{
-    branch::alt,
-    bytes::complete::take,
-    character::complete::{char, i64, u64},
-    multi::many0,
-    sequence::{delimited, terminated, tuple},
-    IResult,
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    io::Write,
+    ops::Index,
 };
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::serde;
+use anyhow::Context;
+use serde::{
+    de::{DeserializeOwned, MapAccess, SeqAccess, Unexpected, Visitor},
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize,
+};
+
+mod de;
+mod ser;
+mod stream;
+mod value;
+
+pub use de::{from_bytes, from_decoded, Deserializer};
+pub use ser::{to_bytes, Serializer};
+pub use stream::{read_value, Event, StreamParser};
+pub use value::Value;
 
 #[derive(Debug, Clone)]
 pub struct Decoded<'a> {
@@ -64,7 +73,8 @@ impl Decoded<'_> {
             DecodedKind::Dict(d) => {
                 write!(writer, "d")?;
                 for (k, v) in d {
-                    write!(writer, "{}:{}", k.len(), k)?;
+                    write!(writer, "{}:", k.len())?;
+                    writer.write_all(k)?;
                     v.encode(writer)?;
                 }
                 write!(writer, "e")?;
@@ -74,14 +84,120 @@ impl Decoded<'_> {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone)]
 pub enum DecodedKind<'a> {
     Bytes(&'a [u8]),
     String(&'a str),
     Int(i64),
     List(Vec<Decoded<'a>>),
-    Dict(HashMap<&'a str, Decoded<'a>>),
+    /// Entries sorted by the bytes of their keys, which is the order they are encoded in
+    Dict(BTreeMap<&'a [u8], Decoded<'a>>),
+}
+
+impl Serialize for DecodedKind<'_> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            DecodedKind::Bytes(b) => s.serialize_bytes(b),
+            DecodedKind::String(string) => s.serialize_str(string),
+            DecodedKind::Int(n) => s.serialize_i64(*n),
+            DecodedKind::List(list) => {
+                let mut seq = s.serialize_seq(Some(list.len()))?;
+                for value in list {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            DecodedKind::Dict(dict) => {
+                let mut map = s.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    // as with values, keys which are valid UTF-8 are strings, which formats such
+                    // as JSON need them to be
+                    match std::str::from_utf8(key) {
+                        Ok(key) => map.serialize_entry(key, value)?,
+                        Err(_) => map.serialize_entry(serde_bytes::Bytes::new(key), value)?,
+                    }
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Values can only be borrowed from the input, so strings, byte strings and keys must be too.
+impl<'de> Deserialize<'de> for DecodedKind<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct KindVisitor;
+
+        impl<'de> Visitor<'de> for KindVisitor {
+            type Value = DecodedKind<'de>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a bencode value")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(DecodedKind::Int(v.into()))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(DecodedKind::Int(v))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map(DecodedKind::Int)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_borrowed_str<E: serde::de::Error>(
+                self,
+                v: &'de str,
+            ) -> Result<Self::Value, E> {
+                Ok(DecodedKind::String(v))
+            }
+
+            fn visit_borrowed_bytes<E: serde::de::Error>(
+                self,
+                v: &'de [u8],
+            ) -> Result<Self::Value, E> {
+                // as when parsing, byte strings which are valid UTF-8 become strings
+                Ok(match std::str::from_utf8(v) {
+                    Ok(s) => DecodedKind::String(s),
+                    Err(_) => DecodedKind::Bytes(v),
+                })
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(DecodedKind::List(list))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut dict = BTreeMap::new();
+                while let Some((key, value)) = map.next_entry::<&'de [u8], _>()? {
+                    dict.insert(key, value);
+                }
+                Ok(DecodedKind::Dict(dict))
+            }
+        }
+
+        deserializer.deserialize_any(KindVisitor)
+    }
 }
 
 impl<'a> DecodedKind<'a> {
@@ -93,17 +209,77 @@ impl<'a> DecodedKind<'a> {
     }
 }
 
+impl<'a> Decoded<'a> {
+    /// The value of `key`, if this is a dictionary holding it.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Decoded<'a>> {
+        self.as_dict()?.get(key.as_ref())
+    }
+
+    /// The value at `path`, which is made of dictionary keys and list indices separated by dots,
+    /// such as `info.files.0.length`.
+    ///
+    /// Keys holding dots can only be reached with [`get`](Self::get).
+    pub fn get_path(&self, path: &str) -> Option<&Decoded<'a>> {
+        path.split('.')
+            .try_fold(self, |value, segment| match &value.kind {
+                DecodedKind::Dict(d) => d.get(segment.as_bytes()),
+                DecodedKind::List(l) => l.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self.kind {
+            DecodedKind::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The contents of a byte string, including one which happens to be valid UTF-8 and so was
+    /// decoded as a string.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.kind {
+            DecodedKind::Bytes(b) => Some(b),
+            DecodedKind::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self.kind {
+            DecodedKind::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Decoded<'a>>> {
+        match &self.kind {
+            DecodedKind::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], Decoded<'a>>> {
+        match &self.kind {
+            DecodedKind::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+/// Panics if the value isn't a dictionary or lacks the key; [`Decoded::get`] doesn't.
 impl<'a> Index<&'_ str> for Decoded<'a> {
     type Output = Decoded<'a>;
 
     fn index(&self, index: &'_ str) -> &Self::Output {
         match &self.kind {
-            DecodedKind::Dict(d) => &d[index],
+            DecodedKind::Dict(d) => &d[index.as_bytes()],
             _ => panic!("Cannot index with string into type other than dictionary"),
         }
     }
 }
 
+/// Panics if the value isn't a list or is too short; [`Decoded::as_list`] doesn't.
 impl<'a> Index<usize> for Decoded<'a> {
     type Output = Decoded<'a>;
 
@@ -117,14 +293,13 @@ impl<'a> Index<usize> for Decoded<'a> {
 
 impl Display for Decoded<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn hex(f: &mut std::fmt::Formatter<'_>, b: &[u8]) -> std::fmt::Result {
+            write!(f, "0x")?;
+            b.iter().try_for_each(|b| write!(f, "{:02x}", b))
+        }
+
         match &self.kind {
-            DecodedKind::Bytes(b) => {
-                write!(f, "0x")?;
-                for b in b.iter() {
-                    write!(f, "{:02x}", b)?;
-                }
-                Ok(())
-            }
+            DecodedKind::Bytes(b) => hex(f, b),
             DecodedKind::String(s) => write!(f, "{}", s),
             DecodedKind::Int(n) => write!(f, "{}", n),
             DecodedKind::List(l) => {
@@ -143,7 +318,11 @@ impl Display for Decoded<'_> {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}: {}", key, value)?;
+                    match std::str::from_utf8(key) {
+                        Ok(key) => write!(f, "{}", key)?,
+                        Err(_) => hex(f, key)?,
+                    }
+                    write!(f, ": {}", value)?;
                 }
                 write!(f, "}}")
             }
@@ -151,76 +330,1020 @@ impl Display for Decoded<'_> {
     }
 }
 
-fn string(encoded: &[u8]) -> IResult<&[u8], Decoded<'_>> {
-    let (rest, len) = terminated(u64, char(':'))(encoded)?;
-    let (rest, s) = take(len)(rest)?;
-    let source = encoded
-        .strip_suffix(rest)
-        .expect("rest is the end of `encoded`");
-    if let Ok(string) = std::str::from_utf8(s) {
-        Ok((rest, DecodedKind::String(string).into_decoded(source)))
+/// How strictly input is held to the canonical encoding.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Reject anything non-canonical
+    Strict,
+    /// Accept anything that can be made sense of, reporting what isn't canonical as warnings
+    #[default]
+    Lenient,
+}
+
+/// Ways in which input can be valid bencode without being canonical.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IssueKind {
+    #[error("leading zero")]
+    LeadingZero,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("explicit plus sign")]
+    PlusSign,
+    #[error("dictionary key {0:?} is out of order")]
+    UnsortedKey(String),
+    #[error("duplicate dictionary key {0:?}")]
+    DuplicateKey(String),
+    #[error("trailing data")]
+    TrailingData,
+}
+
+/// Something non-canonical found at byte `position` of the input.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{kind} at byte {position}")]
+pub struct Issue {
+    pub position: usize,
+    pub kind: IssueKind,
+}
+
+/// Containers nested deeper than this are refused rather than risk overflowing the stack
+pub const MAX_DEPTH: usize = 256;
+
+/// Why input couldn't be decoded, along with the byte offset and the path to the value being
+/// decoded, such as `info.files[3].path`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BencodeError {
+    #[error("unexpected end of input{}", location(*offset, path))]
+    UnexpectedEof { offset: usize, path: String },
+    #[error("invalid integer{}", location(*offset, path))]
+    InvalidInteger { offset: usize, path: String },
+    #[error("invalid string length{}", location(*offset, path))]
+    InvalidLength { offset: usize, path: String },
+    #[error("unexpected byte {byte:#04x}{}", location(*offset, path))]
+    UnexpectedByte {
+        byte: u8,
+        offset: usize,
+        path: String,
+    },
+    #[error("dictionary key is not a string{}", location(*offset, path))]
+    InvalidKey { offset: usize, path: String },
+    #[error("nesting deeper than {MAX_DEPTH}{}", location(*offset, path))]
+    TooDeep { offset: usize, path: String },
+    #[error("trailing data{}", location(*offset, ""))]
+    TrailingData { offset: usize },
+    #[error("{kind}{}", location(*offset, path))]
+    NonCanonical {
+        kind: IssueKind,
+        offset: usize,
+        path: String,
+    },
+    /// A value didn't fit the type it was being (de)serialized as
+    #[error("{message}{}", if path.is_empty() { String::new() } else { format!(" in {}", path) })]
+    Custom { message: String, path: String },
+}
+
+impl BencodeError {
+    /// Offset of the byte at which decoding failed, or `None` for errors in (de)serializing
+    /// values, which aren't tied to a position in any input
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            BencodeError::UnexpectedEof { offset, .. }
+            | BencodeError::InvalidInteger { offset, .. }
+            | BencodeError::InvalidLength { offset, .. }
+            | BencodeError::UnexpectedByte { offset, .. }
+            | BencodeError::InvalidKey { offset, .. }
+            | BencodeError::TooDeep { offset, .. }
+            | BencodeError::TrailingData { offset }
+            | BencodeError::NonCanonical { offset, .. } => Some(offset),
+            BencodeError::Custom { .. } => None,
+        }
+    }
+
+    /// Record that a (de)serialization error happened inside the value at `segment`.
+    fn within(self, segment: Segment<'_>) -> Self {
+        let BencodeError::Custom { message, path } = self else {
+            return self;
+        };
+        let mut prefix = match segment {
+            Segment::Key(key) => String::from_utf8_lossy(key).into_owned(),
+            Segment::Index(index) => format!("[{}]", index),
+        };
+        if !path.is_empty() && !path.starts_with('[') {
+            prefix.push('.');
+        }
+        prefix.push_str(&path);
+        BencodeError::Custom {
+            message,
+            path: prefix,
+        }
+    }
+}
+
+fn location(offset: usize, path: &str) -> String {
+    if path.is_empty() {
+        format!(" at byte {}", offset)
     } else {
-        Ok((rest, DecodedKind::Bytes(s).into_decoded(source)))
+        format!(" at byte {} in {}", offset, path)
     }
 }
 
-fn int(encoded: &[u8]) -> IResult<&[u8], Decoded> {
-    let (rest, n) = delimited(char('i'), i64, char('e'))(encoded)?;
-    let slice = encoded
-        .strip_suffix(rest)
-        .expect("rest is the end of `encoded`");
-    Ok((rest, DecodedKind::Int(n).into_decoded(slice)))
+/// A step from a container to one of its values.
+#[derive(Debug, Clone, Copy)]
+enum Segment<'a> {
+    Key(&'a [u8]),
+    Index(usize),
 }
 
-fn list(encoded: &[u8]) -> IResult<&[u8], Decoded<'_>> {
-    let (rest, vec) = delimited(char('l'), many0(decode), char('e'))(encoded)?;
-    let slice = encoded
-        .strip_suffix(rest)
-        .expect("rest is the end of `encoded`");
-    Ok((rest, DecodedKind::List(vec).into_decoded(slice)))
+/// Format a path in the form `info.files[3].path`.
+fn path<'a>(segments: impl IntoIterator<Item = Segment<'a>>) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Key(key) => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&String::from_utf8_lossy(key));
+            }
+            Segment::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    path
 }
 
-fn dict_entry(encoded: &[u8]) -> IResult<&[u8], (&str, Decoded<'_>)> {
-    let (rest, (key, value)) = tuple((string, decode))(encoded)?;
-    let DecodedKind::String(key) = key.kind else {
-        panic!("should always be string");
-    };
-    Ok((rest, (key, value)))
+/// A recursive descent parser, which notes anything non-canonical as it goes.
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    mode: Mode,
+    issues: Vec<Issue>,
+    /// The containers the parser is inside of
+    path: Vec<Segment<'a>>,
 }
 
-fn dict(encoded: &[u8]) -> IResult<&[u8], Decoded<'_>> {
-    let (rest, vec) = delimited(char('d'), many0(dict_entry), char('e'))(encoded)?;
-    let slice = encoded
-        .strip_suffix(rest)
-        .expect("rest is the end of `encoded`");
-    Ok((
-        rest,
-        DecodedKind::Dict(vec.into_iter().collect()).into_decoded(slice),
-    ))
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8], mode: Mode) -> Self {
+        Self {
+            input,
+            pos: 0,
+            mode,
+            issues: Vec::new(),
+            path: Vec::new(),
+        }
+    }
+
+    fn path(&self) -> String {
+        path(self.path.iter().copied())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn eof(&self) -> BencodeError {
+        BencodeError::UnexpectedEof {
+            offset: self.input.len(),
+            path: self.path(),
+        }
+    }
+
+    /// Note a problem at `offset`, which fails the parse in strict mode.
+    fn issue(&mut self, offset: usize, kind: IssueKind) -> Result<(), BencodeError> {
+        match self.mode {
+            Mode::Strict => Err(BencodeError::NonCanonical {
+                kind,
+                offset,
+                path: self.path(),
+            }),
+            Mode::Lenient => {
+                self.issues.push(Issue {
+                    position: offset,
+                    kind,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Decoded<'a>, BencodeError> {
+        let start = self.pos;
+        let kind = match self.peek() {
+            None => return Err(self.eof()),
+            Some(b'0'..=b'9') => self.string()?,
+            Some(b'i') => self.int()?,
+            Some(b'l') => self.list()?,
+            Some(b'd') => self.dict()?,
+            Some(byte) => {
+                return Err(BencodeError::UnexpectedByte {
+                    byte,
+                    offset: start,
+                    path: self.path(),
+                })
+            }
+        };
+        Ok(kind.into_decoded(&self.input[start..self.pos]))
+    }
+
+    /// Consume the run of ASCII digits at the current position.
+    fn digits(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        &self.input[start..self.pos]
+    }
+
+    fn string(&mut self) -> Result<DecodedKind<'a>, BencodeError> {
+        let s = self.bytes()?;
+        Ok(match std::str::from_utf8(s) {
+            Ok(string) => DecodedKind::String(string),
+            Err(_) => DecodedKind::Bytes(s),
+        })
+    }
+
+    /// The contents of the byte string at the current position.
+    fn bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let invalid = |parser: &Self| BencodeError::InvalidLength {
+            offset: start,
+            path: parser.path(),
+        };
+        let digits = self.digits();
+        let len: usize = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| d.parse().ok())
+            .ok_or_else(|| invalid(self))?;
+        match self.peek() {
+            Some(b':') => self.pos += 1,
+            None => return Err(self.eof()),
+            Some(_) => return Err(invalid(self)),
+        }
+        if digits.len() > 1 && digits[0] == b'0' {
+            self.issue(start, IssueKind::LeadingZero)?;
+        }
+        if self.input.len() - self.pos < len {
+            return Err(self.eof());
+        }
+        let s = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(s)
+    }
+
+    fn int(&mut self) -> Result<DecodedKind<'a>, BencodeError> {
+        self.pos += 1;
+        let start = self.pos;
+        let invalid = |parser: &Self| BencodeError::InvalidInteger {
+            offset: start,
+            path: parser.path(),
+        };
+        let sign = match self.peek() {
+            Some(sign @ (b'-' | b'+')) => {
+                self.pos += 1;
+                Some(sign)
+            }
+            _ => None,
+        };
+        let digits = self.digits();
+        let n: i64 = std::str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .filter(|_| !digits.is_empty())
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| invalid(self))?;
+        match self.peek() {
+            Some(b'e') => self.pos += 1,
+            None => return Err(self.eof()),
+            Some(_) => return Err(invalid(self)),
+        }
+        match (sign, digits) {
+            (Some(b'+'), _) => self.issue(start, IssueKind::PlusSign)?,
+            (Some(b'-'), [b'0', ..]) if n == 0 => self.issue(start, IssueKind::NegativeZero)?,
+            (_, [b'0', _, ..]) => self.issue(start, IssueKind::LeadingZero)?,
+            _ => {}
+        }
+        Ok(DecodedKind::Int(n))
+    }
+
+    /// Step into a list or dictionary.
+    fn enter(&mut self) -> Result<(), BencodeError> {
+        if self.path.len() >= MAX_DEPTH {
+            return Err(BencodeError::TooDeep {
+                offset: self.pos,
+                path: self.path(),
+            });
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<DecodedKind<'a>, BencodeError> {
+        self.enter()?;
+        let mut list = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.eof()),
+                Some(b'e') => break,
+                Some(_) => {
+                    self.path.push(Segment::Index(list.len()));
+                    list.push(self.value()?);
+                    self.path.pop();
+                }
+            }
+        }
+        self.pos += 1;
+        Ok(DecodedKind::List(list))
+    }
+
+    fn dict(&mut self) -> Result<DecodedKind<'a>, BencodeError> {
+        self.enter()?;
+        let mut entries: Vec<(&[u8], Decoded<'a>)> = Vec::new();
+        // while keys arrive in order a duplicate can only be the previous key, so the keys are
+        // only collected once they stop doing so
+        let mut seen: Option<HashSet<&[u8]>> = None;
+        loop {
+            let start = self.pos;
+            match self.peek() {
+                None => return Err(self.eof()),
+                Some(b'e') => break,
+                Some(b'0'..=b'9') => {}
+                Some(_) => {
+                    return Err(BencodeError::InvalidKey {
+                        offset: start,
+                        path: self.path(),
+                    })
+                }
+            }
+            let key = self.bytes()?;
+            let last = entries.last().map(|(k, _)| *k);
+            if seen.is_none() && last.is_some_and(|last| key <= last) {
+                seen = Some(entries.iter().map(|(k, _)| *k).collect());
+            }
+            let duplicate = seen.as_mut().is_some_and(|seen| !seen.insert(key));
+            let lossy = || String::from_utf8_lossy(key).into_owned();
+            if duplicate {
+                self.issue(start, IssueKind::DuplicateKey(lossy()))?;
+            } else if last.is_some_and(|last| key < last) {
+                self.issue(start, IssueKind::UnsortedKey(lossy()))?;
+            }
+            self.path.push(Segment::Key(key));
+            let value = self.value()?;
+            self.path.pop();
+            entries.push((key, value));
+        }
+        self.pos += 1;
+        Ok(DecodedKind::Dict(entries.into_iter().collect()))
+    }
+}
+
+/// Decode the value at the start of `encoded`, returning it along with whatever follows it.
+///
+/// Anything non-canonical is accepted. Input which arrives a piece at a time can be parsed as
+/// it comes with [`StreamParser`].
+pub fn decode(encoded: &[u8]) -> Result<(&[u8], Decoded<'_>), BencodeError> {
+    let mut parser = Parser::new(encoded, Mode::Lenient);
+    let value = parser.value()?;
+    Ok((&encoded[parser.pos..], value))
 }
 
-// TODO: make this support AsyncRead
-pub fn decode(encoded: &[u8]) -> IResult<&[u8], Decoded<'_>> {
-    alt((string, int, list, dict))(encoded)
+/// Decode the single value making up the whole of `encoded`, checking that it is canonical.
+///
+/// In strict mode the first problem found is an error, while in lenient mode every problem is
+/// returned alongside the value.
+pub fn decode_checked(
+    encoded: &[u8],
+    mode: Mode,
+) -> Result<(Decoded<'_>, Vec<Issue>), BencodeError> {
+    let mut parser = Parser::new(encoded, mode);
+    let value = parser.value()?;
+    if parser.pos < encoded.len() {
+        if mode == Mode::Strict {
+            return Err(BencodeError::TrailingData { offset: parser.pos });
+        }
+        parser.issues.push(Issue {
+            position: parser.pos,
+            kind: IssueKind::TrailingData,
+        });
+    }
+    Ok((value, parser.issues))
 }
 
-pub fn decode_into<D>(encoded: &[u8]) -> anyhow::Result<D>
+/// Decode `encoded` straight into a `D`.
+pub fn decode_into<D>(encoded: &[u8]) -> Result<D, BencodeError>
 where
     D: DeserializeOwned,
 {
-    let v = match alt((string, int, list, dict))(encoded) {
-        Ok((_, v)) => v,
-        Err(e) => bail!("Error decoding {:?}", e),
-    };
-    Ok(serde(&v)?)
+    from_bytes(encoded)
 }
 
+/// Write `value` to `writer` as bencode.
 pub fn encode<W, S>(writer: &mut W, value: S) -> anyhow::Result<()>
 where
     W: Write,
     S: Serialize,
 {
-    let string = serde_json::to_string(&value).context("serializing value")?;
-    let decoded: Decoded<'_> = serde_json::from_str(&string).context("deserializing value")?;
-    decoded.encode(writer)
+    let encoded = to_bytes(&value).context("encoding value")?;
+    writer.write_all(&encoded)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let (rest, value) = decode(input).expect("input decodes");
+        assert!(rest.is_empty());
+        let mut buf = Vec::new();
+        value.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn canonical_input_round_trips() {
+        let inputs: &[&[u8]] = &[
+            b"i0e",
+            b"i-42e",
+            b"i9223372036854775807e",
+            b"0:",
+            b"5:hello",
+            b"4:\xff\x00\x01\x80",
+            b"le",
+            b"de",
+            b"l5:helloi52ee",
+            b"d3:cow3:moo4:spam4:eggse",
+            b"d1:ai1e1:bli0eee",
+            b"d4:spaml1:a1:bee",
+            b"d8:announce3:url4:infod6:lengthi5e4:name1:x12:piece lengthi16384e6:pieces20:\
+              \x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10\x11\x12\x13ee",
+        ];
+        for &input in inputs {
+            assert_eq!(
+                round_trip(input),
+                input,
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn keys_are_encoded_in_byte_order() {
+        assert_eq!(round_trip(b"d1:bi1e1:ai2ee"), b"d1:ai2e1:bi1ee");
+        // upper case sorts before lower case, and a prefix before anything it prefixes
+        assert_eq!(
+            round_trip(b"d1:ai0e2:aai0e1:Bi0e1:ai0ee"),
+            b"d1:Bi0e1:ai0e2:aai0ee"
+        );
+    }
+
+    #[test]
+    fn built_dicts_are_sorted() {
+        let dict = BTreeMap::from([
+            (&b"zebra"[..], raw(DecodedKind::Int(1))),
+            (b"\xff", raw(DecodedKind::Int(3))),
+            (b"apple", raw(DecodedKind::Int(2))),
+            (b"mango", raw(DecodedKind::String("x"))),
+        ]);
+        let mut buf = Vec::new();
+        raw(DecodedKind::Dict(dict)).encode(&mut buf).unwrap();
+        assert_eq!(buf, b"d5:applei2e5:mango1:x5:zebrai1e1:\xffi3ee");
+    }
+
+    #[test]
+    fn keys_need_not_be_utf8() {
+        let input = b"d1:ai1e1:\xfei3e2:\xff\x00i2ee";
+        assert_eq!(round_trip(input), input);
+        assert_eq!(round_trip(b"d2:\xff\x00i2e1:ai1e1:\xfei3ee"), input);
+        let (value, _) = decode_checked(input, Mode::Strict).unwrap();
+        assert_eq!(value.get([0xfe]).and_then(Decoded::as_int), Some(3));
+        assert_eq!(value.get("a").and_then(Decoded::as_int), Some(1));
+        assert_eq!(value.to_string(), "{a: 1, 0xfe: 3, 0xff00: 2}");
+        assert_eq!(to_bytes(&value).unwrap(), input);
+    }
+
+    #[test]
+    fn strict_mode_rejects_non_canonical_input() {
+        let cases: &[(&[u8], usize)] = &[
+            (b"i03e", 1),
+            (b"i-0e", 1),
+            (b"03:abc", 0),
+            (b"d1:bi1e1:ai2ee", 7),
+            (b"d1:ai1e1:ai2ee", 7),
+            (b"i3eXX", 3),
+        ];
+        for &(input, position) in cases {
+            let err = decode_checked(input, Mode::Strict).unwrap_err();
+            assert_eq!(err.offset(), Some(position), "{:?}", err);
+            assert_eq!(decode_checked(input, Mode::Lenient).unwrap().1.len(), 1);
+        }
+        assert!(decode_checked(b"d1:ai1e1:bi2ee", Mode::Strict).is_ok());
+    }
+
+    #[test]
+    fn strict_mode_fails_on_issues_lenient_mode_reports() {
+        let cases: &[(&[u8], IssueKind, usize)] = &[
+            (
+                b"d1:bi1e1:ai2ee",
+                IssueKind::UnsortedKey("a".to_string()),
+                7,
+            ),
+            (
+                b"d1:ai1e1:ai2ee",
+                IssueKind::DuplicateKey("a".to_string()),
+                7,
+            ),
+            (b"i03e", IssueKind::LeadingZero, 1),
+            (b"i-0e", IssueKind::NegativeZero, 1),
+        ];
+        for (input, kind, position) in cases {
+            assert_eq!(
+                decode_checked(input, Mode::Strict).unwrap_err(),
+                BencodeError::NonCanonical {
+                    kind: kind.clone(),
+                    offset: *position,
+                    path: String::new(),
+                }
+            );
+            let (_, issues) = decode_checked(input, Mode::Lenient).unwrap();
+            assert_eq!(
+                issues,
+                [Issue {
+                    position: *position,
+                    kind: kind.clone(),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_keys_are_found_wherever_they_are() {
+        // out of order, with keys repeated well after they first appear
+        let input = b"d1:ci0e1:bi0e1:di0e1:ai0e1:ci1e1:ai2ee";
+        let (value, issues) = decode_checked(input, Mode::Lenient).unwrap();
+        let key = |s: &str| s.to_string();
+        assert_eq!(
+            issues
+                .into_iter()
+                .map(|i| (i.position, i.kind))
+                .collect::<Vec<_>>(),
+            [
+                (7, IssueKind::UnsortedKey(key("b"))),
+                (19, IssueKind::UnsortedKey(key("a"))),
+                (25, IssueKind::DuplicateKey(key("c"))),
+                (31, IssueKind::DuplicateKey(key("a"))),
+            ]
+        );
+        // the last of a repeated key wins
+        assert_eq!(value.get("a").and_then(Decoded::as_int), Some(2));
+
+        let err = decode_checked(b"d1:ai0e1:bi0e1:bi1ee", Mode::Strict).unwrap_err();
+        assert!(matches!(
+            err,
+            BencodeError::NonCanonical {
+                kind: IssueKind::DuplicateKey(_),
+                offset: 13,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn errors_give_offset_and_path() {
+        let err = decode(b"d4:infod5:filesld4:pathl1:axeeee").unwrap_err();
+        assert_eq!(
+            err,
+            BencodeError::UnexpectedByte {
+                byte: b'x',
+                offset: 27,
+                path: "info.files[0].path[1]".to_string(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "unexpected byte 0x78 at byte 27 in info.files[0].path[1]"
+        );
+
+        let eof = BencodeError::UnexpectedEof {
+            offset: 6,
+            path: "[0]".to_string(),
+        };
+        assert_eq!(decode(b"l5:hel").unwrap_err(), eof);
+        let cases: &[(&[u8], BencodeError)] = &[
+            (
+                b"i12x",
+                BencodeError::InvalidInteger {
+                    offset: 1,
+                    path: String::new(),
+                },
+            ),
+            (
+                b"ie",
+                BencodeError::InvalidInteger {
+                    offset: 1,
+                    path: String::new(),
+                },
+            ),
+            (
+                b"i99999999999999999999e",
+                BencodeError::InvalidInteger {
+                    offset: 1,
+                    path: String::new(),
+                },
+            ),
+            (
+                b"5x",
+                BencodeError::InvalidLength {
+                    offset: 0,
+                    path: String::new(),
+                },
+            ),
+            (
+                b"di1ei2ee",
+                BencodeError::InvalidKey {
+                    offset: 1,
+                    path: String::new(),
+                },
+            ),
+        ];
+        for (input, error) in cases {
+            assert_eq!(&decode(input).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let deep = vec![b'l'; MAX_DEPTH + 1];
+        assert!(matches!(
+            decode(&deep).unwrap_err(),
+            BencodeError::TooDeep {
+                offset: MAX_DEPTH,
+                ..
+            }
+        ));
+        let mut ok = vec![b'l'; MAX_DEPTH];
+        ok.extend(vec![b'e'; MAX_DEPTH]);
+        assert_eq!(round_trip(&ok), ok);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        #[serde(with = "serde_bytes")]
+        id: Vec<u8>,
+        name: String,
+        port: u16,
+        #[serde(default)]
+        token: Option<String>,
+        seed: bool,
+        values: Vec<i64>,
+        kind: Kind,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Kind {
+        Ping,
+        Find { target: [u8; 4] },
+    }
+
+    #[test]
+    fn structs_round_trip_through_bencode() {
+        let message = Message {
+            id: vec![0xff, 0, 1, 0x80],
+            name: "node".to_string(),
+            port: 6881,
+            token: None,
+            seed: true,
+            values: vec![-1, 2],
+            kind: Kind::Find { target: *b"abcd" },
+        };
+        let encoded = to_bytes(&message).unwrap();
+        // keys come out sorted and `None` is left out
+        assert_eq!(
+            encoded,
+            b"d2:id4:\xff\x00\x01\x804:kindd4:findd6:targetli97ei98ei99ei100eeee\
+              4:name4:node4:porti6881e4:seedi1e6:valuesli-1ei2eee"
+        );
+        assert_eq!(from_bytes::<Message>(&encoded).unwrap(), message);
+        assert_eq!(to_bytes(&Kind::Ping).unwrap(), b"4:ping");
+        assert_eq!(from_bytes::<Kind>(b"4:ping").unwrap(), Kind::Ping);
+    }
+
+    #[test]
+    fn byte_strings_deserialize_as_strings_bytes_or_arrays() {
+        #[derive(Debug, Deserialize)]
+        struct Borrowed<'a> {
+            s: &'a str,
+            #[serde(with = "serde_bytes")]
+            b: &'a [u8],
+            a: [u8; 2],
+        }
+        let input = b"d1:a2:hi1:b2:\xff\xfe1:s3:abce";
+        let value: Borrowed = from_bytes(input).unwrap();
+        assert_eq!(value.s, "abc");
+        assert_eq!(value.b, b"\xff\xfe");
+        assert_eq!(&value.a, b"hi");
+        assert!(from_bytes::<[u8; 3]>(b"2:hi").is_err());
+    }
+
+    #[test]
+    fn torrents_keep_binary_pieces() {
+        let input = b"d8:announce3:url4:infod6:lengthi5e4:name1:x12:piece lengthi16384e6:pieces20:\
+              \xff\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10\x11\x12\x13ee";
+        let torrent: crate::Torrent = from_bytes(input).unwrap();
+        assert_eq!(torrent.announce, "url");
+        assert_eq!(torrent.info.length(), 5);
+        assert_eq!(torrent.info.pieces[0], 0xff);
+        assert_eq!(torrent.info.pieces.len(), 20);
+    }
+
+    #[test]
+    fn type_errors_give_path() {
+        let input = b"d4:infod4:name1:x12:piece length3:big6:pieces0:6:lengthi1eee";
+        let err = from_bytes::<crate::Torrent>(input).unwrap_err();
+        assert_eq!(decode_into::<crate::Torrent>(input).unwrap_err(), err);
+        assert_eq!(err.offset(), None);
+        assert_eq!(
+            err.to_string(),
+            "invalid type: string \"big\", expected u32 in info.piece length"
+        );
+        let err = to_bytes(&vec![Some(1), None]).unwrap_err();
+        assert_eq!(err.to_string(), "lists can't hold `None` or `()` in [1]");
+        assert!(to_bytes(&1.5).is_err());
+    }
+
+    #[test]
+    fn decoded_values_serialize_as_themselves() {
+        let input = b"d1:ai1e1:bl4:\xff\x00\x01\x803:abcee";
+        let (_, value) = decode(input).unwrap();
+        assert_eq!(to_bytes(&value).unwrap(), input);
+        let copy: Decoded = from_decoded(&value).unwrap();
+        assert_eq!(copy.to_string(), value.to_string());
+    }
+
+    #[test]
+    fn binary_keys_round_trip_through_serde() {
+        let input = b"d1:ai1e2:\xff\x00i2ee";
+        let value: Value = from_bytes(input).unwrap();
+        assert_eq!(value.get([0xff, 0]), Some(&Value::Int(2)));
+        assert_eq!(to_bytes(&value).unwrap(), input);
+
+        let map: BTreeMap<serde_bytes::ByteBuf, i64> = from_bytes(input).unwrap();
+        assert_eq!(to_bytes(&map).unwrap(), input);
+        let decoded: Decoded = from_bytes(input).unwrap();
+        assert_eq!(to_bytes(&decoded).unwrap(), input);
+
+        // fields are still found by name, and unknown binary keys skipped
+        #[derive(Debug, Deserialize)]
+        struct Fields {
+            a: i64,
+        }
+        assert_eq!(from_bytes::<Fields>(input).unwrap().a, 1);
+        let torrent =
+            b"d4:infod2:\xff\xfei0e6:lengthi5e4:name1:x12:piece lengthi16384e6:pieces0:ee";
+        assert_eq!(
+            from_bytes::<crate::Torrent>(torrent).unwrap().info.length(),
+            5
+        );
+    }
+
+    /// Feed `input` to a stream parser in chunks of `size` bytes, collecting its events.
+    fn events(input: &[u8], size: usize) -> Result<Vec<Event>, BencodeError> {
+        let mut parser = StreamParser::new();
+        let mut events = Vec::new();
+        for mut chunk in input.chunks(size) {
+            while !chunk.is_empty() && !parser.is_done() {
+                let (consumed, event) = parser.parse(chunk)?;
+                events.extend(event);
+                chunk = &chunk[consumed..];
+            }
+        }
+        parser.end_of_input()?;
+        Ok(events)
+    }
+
+    #[test]
+    fn stream_events_do_not_depend_on_chunking() {
+        let input = b"d4:infod6:lengthi-12e4:name5:hello6:pieces4:\xff\x00\x01\x80e4:listl0:leeee";
+        let expected = vec![
+            Event::DictStart,
+            Event::Key(b"info".to_vec()),
+            Event::DictStart,
+            Event::Key(b"length".to_vec()),
+            Event::Int(-12),
+            Event::Key(b"name".to_vec()),
+            Event::Bytes(b"hello".to_vec()),
+            Event::Key(b"pieces".to_vec()),
+            Event::Bytes(vec![0xff, 0, 1, 0x80]),
+            Event::End,
+            Event::Key(b"list".to_vec()),
+            Event::ListStart,
+            Event::Bytes(Vec::new()),
+            Event::ListStart,
+            Event::End,
+            Event::End,
+            Event::End,
+        ];
+        for size in 1..=input.len() {
+            assert_eq!(events(input, size).unwrap(), expected, "chunks of {}", size);
+        }
+    }
+
+    #[test]
+    fn stream_errors_match_decode() {
+        let inputs: &[&[u8]] = &[
+            b"d4:infod5:filesld4:pathl1:axeeee",
+            b"l5:hel",
+            b"i12x",
+            b"ie",
+            b"i99999999999999999999e",
+            b"5x",
+            b"di1ei2ee",
+            b"d1:ae",
+            b"",
+        ];
+        for &input in inputs {
+            let expected = decode(input).unwrap_err();
+            for size in [1, 3, input.len().max(1)] {
+                assert_eq!(events(input, size).unwrap_err(), expected, "{:?}", input);
+            }
+        }
+        let deep = vec![b'l'; MAX_DEPTH + 1];
+        assert_eq!(events(&deep, 7).unwrap_err(), decode(&deep).unwrap_err());
+    }
+
+    #[tokio::test]
+    async fn read_value_stops_at_the_end_of_the_value() {
+        let input = b"d3:cow3:moo4:spaml1:ai2eee and then some";
+        let mut reader = tokio::io::BufReader::with_capacity(4, &input[..]);
+        let value = read_value(&mut reader).await.unwrap();
+        assert_eq!(value, b"d3:cow3:moo4:spaml1:ai2eee");
+        let mut rest = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut rest)
+            .await
+            .unwrap();
+        assert_eq!(rest, " and then some");
+
+        let mut parser = StreamParser::new();
+        let mut reader = tokio::io::BufReader::with_capacity(2, &b"l4:spamei3e"[..]);
+        let mut events = Vec::new();
+        while let Some(event) = parser.next_event(&mut reader).await.unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            [Event::ListStart, Event::Bytes(b"spam".to_vec()), Event::End]
+        );
+        assert_eq!(parser.offset(), 8);
+        assert!(read_value(&mut &b"l4:sp"[..]).await.is_err());
+    }
+
+    #[test]
+    fn values_are_built_and_encoded_sorted() {
+        let value = Value::dict()
+            .with("info", Value::dict().with("length", 5).with("name", "x"))
+            .with("announce", "url")
+            .with("list", vec![Value::Int(-1), "a".into()])
+            .with(vec![0xff], vec![0x00, 0x80]);
+        let mut buf = Vec::new();
+        value.encode(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            b"d8:announce3:url4:infod6:lengthi5e4:name1:xe4:listli-1e1:ae1:\xff2:\x00\x80e"
+        );
+        assert_eq!(Value::decode(&buf).unwrap(), value);
+        assert_eq!(to_bytes(&value).unwrap(), buf);
+        assert_eq!(from_bytes::<Value>(b"d1:ai1e1:b2:hie").unwrap(), value_ab());
+    }
+
+    fn value_ab() -> Value {
+        [("a", Value::Int(1)), ("b", "hi".into())]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn values_can_be_edited_by_path() {
+        let mut torrent =
+            Value::decode(b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi4e4:pathl1:beeeee")
+                .unwrap();
+        assert_eq!(
+            torrent.get_path("info.files.1.length"),
+            Some(&Value::Int(4))
+        );
+        assert_eq!(
+            torrent
+                .get_path("info.files.0.path.0")
+                .and_then(Value::as_str),
+            Some("a")
+        );
+        assert_eq!(torrent.get_path("info.files.2"), None);
+        assert_eq!(torrent.get_path("info.files.x"), None);
+        assert_eq!(torrent.get_path("info.nope"), None);
+
+        *torrent.get_path_mut("info.files.0.length").unwrap() = 7.into();
+        torrent.get_mut("info").unwrap().insert("private", true);
+        torrent.insert("comment", "edited");
+        assert_eq!(
+            torrent
+                .remove("comment")
+                .and_then(|c| c.as_str().map(str::to_string)),
+            Some("edited".to_string())
+        );
+        assert_eq!(torrent.remove("comment"), None);
+        let mut buf = Vec::new();
+        torrent.encode(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            b"d4:infod5:filesld6:lengthi7e4:pathl1:aeed6:lengthi4e4:pathl1:beee7:privatei1eee"
+        );
+    }
+
+    #[test]
+    fn values_convert_to_and_from_decoded() {
+        let input = b"d1:ai1e1:bl4:\xff\x00\x01\x803:abcee";
+        let (_, decoded) = decode(input).unwrap();
+        let value = Value::from(&decoded);
+        assert_eq!(Value::decode(input).unwrap(), value);
+        let back = Decoded::from(&value);
+        let mut buf = Vec::new();
+        back.encode(&mut buf).unwrap();
+        assert_eq!(buf, input);
+        assert_eq!(back.to_string(), value.to_string());
+
+        let binary_key = Value::decode(b"d1:\xffi1ee").unwrap();
+        assert_eq!(binary_key.get([0xff]), Some(&Value::Int(1)));
+        let mut buf = Vec::new();
+        Decoded::from(&binary_key).encode(&mut buf).unwrap();
+        assert_eq!(buf, b"d1:\xffi1ee");
+        assert!(matches!(
+            Value::decode(b"i1ei2e").unwrap_err(),
+            BencodeError::TrailingData { offset: 3 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn values_can_be_read_from_a_stream() {
+        let mut reader = tokio::io::BufReader::with_capacity(3, &b"d1:ai1e1:b2:hiei5e"[..]);
+        assert_eq!(Value::read(&mut reader).await.unwrap(), value_ab());
+        assert_eq!(Value::read(&mut reader).await.unwrap(), Value::Int(5));
+        assert!(Value::read(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn accessors_return_none_instead_of_panicking() {
+        let input = b"d4:infod5:filesld6:lengthi3e4:pathl1:aeee6:pieces2:\xff\x00ee";
+        let (_, value) = decode(input).unwrap();
+        assert_eq!(
+            value
+                .get_path("info.files.0.length")
+                .and_then(Decoded::as_int),
+            Some(3)
+        );
+        assert_eq!(
+            value
+                .get_path("info.files.0.path.0")
+                .and_then(Decoded::as_str),
+            Some("a")
+        );
+        assert_eq!(
+            value.get_path("info.pieces").and_then(Decoded::as_bytes),
+            Some(&b"\xff\x00"[..])
+        );
+        // strings are byte strings too, but byte strings aren't necessarily strings
+        assert_eq!(
+            value
+                .get_path("info.files.0.path.0")
+                .and_then(Decoded::as_bytes),
+            Some(&b"a"[..])
+        );
+        assert!(value
+            .get_path("info.pieces")
+            .and_then(Decoded::as_str)
+            .is_none());
+
+        for path in [
+            "info.files.1",
+            "info.files.x",
+            "info.nope",
+            "info.pieces.0",
+            "",
+        ] {
+            assert!(value.get_path(path).is_none(), "{}", path);
+        }
+        assert!(value.get("nope").is_none());
+        assert!(value.as_list().is_none());
+        assert!(value.as_int().is_none());
+        assert_eq!(
+            value
+                .get("info")
+                .and_then(Decoded::as_dict)
+                .map(BTreeMap::len),
+            Some(2)
+        );
+        let files = value.get_path("info.files").and_then(Decoded::as_list);
+        assert_eq!(files.map(Vec::len), Some(1));
+        assert!(files.unwrap()[0].get("length").is_some());
+    }
+
+    fn raw(kind: DecodedKind<'_>) -> Decoded<'_> {
+        Decoded { source: None, kind }
+    }
 }
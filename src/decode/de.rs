@@ -0,0 +1,315 @@
+//! Deserializing Rust types straight from decoded bencode values.
+
+use std::{collections::btree_map, fmt::Display, slice};
+
+use serde::de::{
+    self,
+    value::{BorrowedBytesDeserializer, BorrowedStrDeserializer, SeqDeserializer},
+    DeserializeSeed, IntoDeserializer, Visitor,
+};
+
+use super::{decode, BencodeError, Decoded, DecodedKind, Segment};
+
+/// Deserialize a `T` from the single value making up the whole of `encoded`.
+///
+/// Strings and byte strings are borrowed from `encoded` where `T` allows it.
+pub fn from_bytes<'de, T>(encoded: &'de [u8]) -> Result<T, BencodeError>
+where
+    T: de::Deserialize<'de>,
+{
+    let (rest, value) = decode(encoded)?;
+    if !rest.is_empty() {
+        return Err(BencodeError::TrailingData {
+            offset: encoded.len() - rest.len(),
+        });
+    }
+    from_decoded(&value)
+}
+
+/// Deserialize a `T` from an already decoded value.
+pub fn from_decoded<'de, T>(value: &Decoded<'de>) -> Result<T, BencodeError>
+where
+    T: de::Deserialize<'de>,
+{
+    T::deserialize(Deserializer::new(value))
+}
+
+impl de::Error for BencodeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BencodeError::Custom {
+            message: msg.to_string(),
+            path: String::new(),
+        }
+    }
+}
+
+/// Hands a [`Decoded`] value to serde.
+///
+/// Byte strings can be deserialized as bytes (with `serde_bytes`), as sequences of `u8` (such
+/// as `[u8; 20]`), or as strings when they are valid UTF-8. Integers 0 and 1 can be booleans.
+#[derive(Debug, Clone, Copy)]
+pub struct Deserializer<'a, 'de> {
+    value: &'a Decoded<'de>,
+}
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    pub fn new(value: &'a Decoded<'de>) -> Self {
+        Self { value }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_, 'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match &self.value.kind {
+            DecodedKind::Bytes(b) => visitor.visit_borrowed_bytes(b),
+            DecodedKind::String(s) => visitor.visit_borrowed_str(s),
+            DecodedKind::Int(n) => visitor.visit_i64(*n),
+            DecodedKind::List(list) => visitor.visit_seq(SeqAccess {
+                iter: list.iter().enumerate(),
+            }),
+            DecodedKind::Dict(dict) => visitor.visit_map(MapAccess {
+                iter: dict.iter(),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value.kind {
+            DecodedKind::Int(n @ (0 | 1)) => visitor.visit_bool(n == 1),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value.kind {
+            DecodedKind::String(s) => visitor.visit_borrowed_bytes(s.as_bytes()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let bytes = match self.value.kind {
+            DecodedKind::Bytes(b) => b,
+            DecodedKind::String(s) => s.as_bytes(),
+            _ => return self.deserialize_any(visitor),
+        };
+        let mut seq = SeqDeserializer::<_, BencodeError>::new(bytes.iter().copied());
+        let value = visitor.visit_seq(&mut seq)?;
+        seq.end()?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        // absent keys are the only way to leave a value out
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        // unit variants are strings, and the others dictionaries with the variant as only key
+        match &self.value.kind {
+            DecodedKind::String(s) => visitor.visit_enum(BorrowedStrDeserializer::new(s)),
+            DecodedKind::Dict(dict) if dict.len() == 1 => {
+                let (variant, value) = dict.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess { variant, value })
+            }
+            _ => Err(de::Error::invalid_type(self.unexpected(), &"enum")),
+        }
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct tuple_struct map struct identifier
+    }
+}
+
+impl Deserializer<'_, '_> {
+    fn unexpected(&self) -> de::Unexpected<'_> {
+        match &self.value.kind {
+            DecodedKind::Bytes(b) => de::Unexpected::Bytes(b),
+            DecodedKind::String(s) => de::Unexpected::Str(s),
+            DecodedKind::Int(n) => de::Unexpected::Signed(*n),
+            DecodedKind::List(_) => de::Unexpected::Seq,
+            DecodedKind::Dict(_) => de::Unexpected::Map,
+        }
+    }
+}
+
+impl<'a, 'de> IntoDeserializer<'de, BencodeError> for &'a Decoded<'de> {
+    type Deserializer = Deserializer<'a, 'de>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        Deserializer::new(self)
+    }
+}
+
+struct SeqAccess<'a, 'de> {
+    iter: std::iter::Enumerate<slice::Iter<'a, Decoded<'de>>>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'_, 'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let Some((index, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        seed.deserialize(Deserializer::new(value))
+            .map(Some)
+            .map_err(|e| e.within(Segment::Index(index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapAccess<'a, 'de> {
+    iter: btree_map::Iter<'a, &'de [u8], Decoded<'de>>,
+    /// The entry whose key was handed out last
+    value: Option<(&'de [u8], &'a Decoded<'de>)>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'_, 'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        let Some((&key, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, value));
+        // keys needn't be UTF-8, and field names and strings can be read from bytes anyway
+        seed.deserialize(BorrowedBytesDeserializer::new(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let (key, value) = self
+            .value
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        seed.deserialize(Deserializer::new(value))
+            .map_err(|e| e.within(Segment::Key(key)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumAccess<'a, 'de> {
+    variant: &'de [u8],
+    value: &'a Decoded<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for EnumAccess<'a, 'de> {
+    type Error = BencodeError;
+    type Variant = Deserializer<'a, 'de>;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(BorrowedBytesDeserializer::new(self.variant))?;
+        Ok((variant, Deserializer::new(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer<'_, 'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(de::Error::invalid_type(self.unexpected(), &"unit variant"))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
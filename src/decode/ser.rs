@@ -0,0 +1,495 @@
+//! Serializing Rust types straight to bencode.
+
+use std::fmt::Display;
+
+use serde::ser::{self, Serialize};
+
+use super::{decode, BencodeError, DecodedKind, Segment};
+
+/// Encode `value` as bencode.
+///
+/// Dictionaries are written with their keys sorted, so the output is canonical. Fields and map
+/// entries holding `None` or `()` are left out, as bencode has no way of writing nothing.
+pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>, BencodeError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer::new();
+    value.serialize(&mut serializer)?;
+    if serializer.output.is_empty() {
+        return Err(ser::Error::custom("nothing to encode"));
+    }
+    Ok(serializer.into_inner())
+}
+
+impl ser::Error for BencodeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BencodeError::Custom {
+            message: msg.to_string(),
+            path: String::new(),
+        }
+    }
+}
+
+/// Writes bencode into a buffer.
+///
+/// Byte slices serialize as lists of integers unless they are marked with `serde_bytes`. Floats
+/// can't be represented and are an error.
+#[derive(Debug, Default)]
+pub struct Serializer {
+    output: Vec<u8>,
+}
+
+impl Serializer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The encoded output written so far.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.output
+    }
+
+    fn int(&mut self, n: impl Display) {
+        self.output.extend_from_slice(format!("i{}e", n).as_bytes());
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.output
+            .extend_from_slice(format!("{}:", s.len()).as_bytes());
+        self.output.extend_from_slice(s);
+    }
+
+    /// Start a dictionary holding `variant` as its only key, for the enum variants which carry
+    /// values.
+    fn variant(&mut self, variant: &str) {
+        self.output.push(b'd');
+        self.string(variant.as_bytes());
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = BencodeError;
+
+    type SerializeSeq = List<'a>;
+    type SerializeTuple = List<'a>;
+    type SerializeTupleStruct = List<'a>;
+    type SerializeTupleVariant = List<'a>;
+    type SerializeMap = Dict<'a>;
+    type SerializeStruct = Dict<'a>;
+    type SerializeStructVariant = Dict<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), BencodeError> {
+        self.int(u8::from(v));
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), BencodeError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), BencodeError> {
+        Err(ser::Error::custom("bencode can't represent floats"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), BencodeError> {
+        Err(ser::Error::custom("bencode can't represent floats"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), BencodeError> {
+        self.string(v.encode_utf8(&mut [0; 4]).as_bytes());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), BencodeError> {
+        self.string(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), BencodeError> {
+        self.string(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), BencodeError> {
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), BencodeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), BencodeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), BencodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.variant(variant);
+        value.serialize(&mut *self)?;
+        self.output.push(b'e');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<List<'a>, BencodeError> {
+        self.output.push(b'l');
+        Ok(List {
+            ser: self,
+            len: 0,
+            variant: false,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<List<'a>, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<List<'a>, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<List<'a>, BencodeError> {
+        self.variant(variant);
+        self.output.push(b'l');
+        Ok(List {
+            ser: self,
+            len: 0,
+            variant: true,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Dict<'a>, BencodeError> {
+        Ok(Dict {
+            ser: self,
+            entries: Vec::new(),
+            key: None,
+            variant: false,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Dict<'a>, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Dict<'a>, BencodeError> {
+        self.variant(variant);
+        Ok(Dict {
+            ser: self,
+            entries: Vec::new(),
+            key: None,
+            variant: true,
+        })
+    }
+}
+
+/// Serializes the elements of a list straight into the output.
+pub struct List<'a> {
+    ser: &'a mut Serializer,
+    /// Elements written so far
+    len: usize,
+    /// Whether the list is wrapped in a dictionary naming an enum variant
+    variant: bool,
+}
+
+impl List<'_> {
+    fn element<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let start = self.ser.output.len();
+        value
+            .serialize(&mut *self.ser)
+            .map_err(|e| e.within(Segment::Index(self.len)))?;
+        if self.ser.output.len() == start {
+            let error: BencodeError = ser::Error::custom("lists can't hold `None` or `()`");
+            return Err(error.within(Segment::Index(self.len)));
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), BencodeError> {
+        self.ser.output.push(b'e');
+        if self.variant {
+            self.ser.output.push(b'e');
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for List<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for List<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for List<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for List<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+/// Collects the entries of a dictionary, which can only be written once they are all known and
+/// sorted.
+pub struct Dict<'a> {
+    ser: &'a mut Serializer,
+    /// Encoded keys and values
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The key of the entry whose value comes next
+    key: Option<Vec<u8>>,
+    /// Whether the dictionary is wrapped in another naming an enum variant
+    variant: bool,
+}
+
+impl Dict<'_> {
+    fn entry<T>(&mut self, key: Vec<u8>, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut serializer = Serializer::new();
+        value
+            .serialize(&mut serializer)
+            .map_err(|e| e.within(Segment::Key(&key)))?;
+        // leave out entries with nothing to write rather than invent a value for them
+        if !serializer.output.is_empty() {
+            self.entries.push((key, serializer.output));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), BencodeError> {
+        let Dict {
+            ser,
+            mut entries,
+            variant,
+            ..
+        } = self;
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(ser::Error::custom(format!(
+                "duplicate dictionary key {:?}",
+                String::from_utf8_lossy(&pair[0].0)
+            )));
+        }
+        ser.output.push(b'd');
+        for (key, value) in entries {
+            ser.string(&key);
+            ser.output.extend_from_slice(&value);
+        }
+        ser.output.push(b'e');
+        if variant {
+            ser.output.push(b'e');
+        }
+        Ok(())
+    }
+}
+
+/// Serialize a dictionary key, which has to come out as a string.
+fn encode_key<T>(key: &T) -> Result<Vec<u8>, BencodeError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer::new();
+    key.serialize(&mut serializer)?;
+    match decode(&serializer.output).map(|(_, key)| key.kind) {
+        Ok(DecodedKind::String(s)) => Ok(s.as_bytes().to_vec()),
+        Ok(DecodedKind::Bytes(b)) => Ok(b.to_vec()),
+        _ => Err(ser::Error::custom("dictionary keys must be strings")),
+    }
+}
+
+impl ser::SerializeMap for Dict<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.key = Some(encode_key(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.entry(key, value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Dict<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Dict<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), BencodeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
@@ -0,0 +1,309 @@
+//! Incremental parsing, for bencode which arrives a piece at a time.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use super::{path, BencodeError, Segment, MAX_DEPTH};
+
+/// Longest integer accepted, which is enough for any `i64` with a sign and leading zero
+const MAX_INT_LEN: usize = 21;
+
+/// A piece of the structure of a bencoded value, in the order it appears in the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Int(i64),
+    /// A byte string other than a dictionary key
+    Bytes(Vec<u8>),
+    /// A dictionary key, which is followed by the events of its value
+    Key(Vec<u8>),
+    ListStart,
+    DictStart,
+    /// The end of the innermost list or dictionary
+    End,
+}
+
+/// A list or dictionary the parser is inside of.
+#[derive(Debug, Clone)]
+enum Container {
+    /// Holding the index of the element being parsed
+    List(usize),
+    /// Holding the key of the value being parsed, or `None` while expecting a key
+    Dict(Option<Vec<u8>>),
+}
+
+/// What the parser is in the middle of.
+#[derive(Debug, Clone)]
+enum State {
+    /// Between values
+    Value,
+    /// Reading the length of a string, which started at `start`
+    Length { start: usize, len: usize },
+    /// Reading the contents of a string
+    String { remaining: usize, buf: Vec<u8> },
+    /// Reading the digits of an integer, which started at `start`
+    Int { start: usize, buf: Vec<u8> },
+    /// A whole value has been read
+    Done,
+}
+
+/// Parses a single value from input fed to it in chunks of any size, emitting [`Event`]s as
+/// they complete.
+///
+/// Only the string or integer being read is buffered. As with [`decode`](super::decode),
+/// non-canonical input is accepted.
+#[derive(Debug, Clone)]
+pub struct StreamParser {
+    state: State,
+    stack: Vec<Container>,
+    /// Bytes consumed so far
+    offset: usize,
+}
+
+impl Default for StreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamParser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Value,
+            stack: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Whether a whole value has been read, after which no more input is consumed.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Parse `input`, which continues from where the last call left off, up to the end of the
+    /// next event.
+    ///
+    /// Returns how many bytes of `input` were consumed along with the event, if one completed.
+    /// Without an event, all of `input` has been consumed and more is needed, unless the value
+    /// is done. The parser can't be used again after returning an error.
+    pub fn parse(&mut self, input: &[u8]) -> Result<(usize, Option<Event>), BencodeError> {
+        let mut consumed = 0;
+        while consumed < input.len() && !self.is_done() {
+            let (n, event) = self.step(&input[consumed..])?;
+            consumed += n;
+            self.offset += n;
+            if event.is_some() {
+                return Ok((consumed, event));
+            }
+        }
+        Ok((consumed, None))
+    }
+
+    /// Consume at least one byte of `input`, which isn't empty.
+    fn step(&mut self, input: &[u8]) -> Result<(usize, Option<Event>), BencodeError> {
+        let byte = input[0];
+        match std::mem::replace(&mut self.state, State::Value) {
+            State::Done => unreachable!("parsing after the value is done"),
+            State::Value => Ok((1, self.start(byte)?)),
+            State::Length { start, len } => match byte {
+                b'0'..=b'9' => {
+                    let len = len
+                        .checked_mul(10)
+                        .and_then(|len| len.checked_add(usize::from(byte - b'0')))
+                        .ok_or_else(|| BencodeError::InvalidLength {
+                            offset: start,
+                            path: self.path(),
+                        })?;
+                    self.state = State::Length { start, len };
+                    Ok((1, None))
+                }
+                b':' if len == 0 => Ok((1, Some(self.string(Vec::new())))),
+                b':' => {
+                    self.state = State::String {
+                        remaining: len,
+                        // the length is only a claim, so don't trust it with a huge allocation
+                        buf: Vec::with_capacity(std::cmp::min(len, 1 << 16)),
+                    };
+                    Ok((1, None))
+                }
+                _ => Err(BencodeError::InvalidLength {
+                    offset: start,
+                    path: self.path(),
+                }),
+            },
+            State::String { remaining, mut buf } => {
+                // take as much of the string as is here in one go
+                let n = std::cmp::min(remaining, input.len());
+                buf.extend_from_slice(&input[..n]);
+                if n == remaining {
+                    return Ok((n, Some(self.string(buf))));
+                }
+                self.state = State::String {
+                    remaining: remaining - n,
+                    buf,
+                };
+                Ok((n, None))
+            }
+            State::Int { start, mut buf } => match byte {
+                b'e' => {
+                    let n = std::str::from_utf8(&buf)
+                        .ok()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| BencodeError::InvalidInteger {
+                            offset: start,
+                            path: self.path(),
+                        })?;
+                    self.finish_value();
+                    Ok((1, Some(Event::Int(n))))
+                }
+                b'0'..=b'9' | b'-' | b'+'
+                    if buf.len() < MAX_INT_LEN && (byte.is_ascii_digit() || buf.is_empty()) =>
+                {
+                    buf.push(byte);
+                    self.state = State::Int { start, buf };
+                    Ok((1, None))
+                }
+                _ => Err(BencodeError::InvalidInteger {
+                    offset: start,
+                    path: self.path(),
+                }),
+            },
+        }
+    }
+
+    /// Handle the first byte of a value, or the end of a container.
+    fn start(&mut self, byte: u8) -> Result<Option<Event>, BencodeError> {
+        let offset = self.offset;
+        let expecting_key = matches!(self.stack.last(), Some(Container::Dict(None)));
+        match byte {
+            b'e' if matches!(self.stack.last(), Some(Container::List(_))) || expecting_key => {
+                self.stack.pop();
+                self.finish_value();
+                Ok(Some(Event::End))
+            }
+            b'0'..=b'9' => {
+                self.state = State::Length {
+                    start: offset,
+                    len: usize::from(byte - b'0'),
+                };
+                Ok(None)
+            }
+            _ if expecting_key => Err(BencodeError::InvalidKey {
+                offset,
+                path: self.path(),
+            }),
+            b'i' => {
+                self.state = State::Int {
+                    start: offset + 1,
+                    buf: Vec::new(),
+                };
+                Ok(None)
+            }
+            b'l' | b'd' => {
+                if self.stack.len() >= MAX_DEPTH {
+                    return Err(BencodeError::TooDeep {
+                        offset,
+                        path: self.path(),
+                    });
+                }
+                if byte == b'l' {
+                    self.stack.push(Container::List(0));
+                    Ok(Some(Event::ListStart))
+                } else {
+                    self.stack.push(Container::Dict(None));
+                    Ok(Some(Event::DictStart))
+                }
+            }
+            byte => Err(BencodeError::UnexpectedByte {
+                byte,
+                offset,
+                path: self.path(),
+            }),
+        }
+    }
+
+    /// Handle a complete string, which is either a dictionary key or a value.
+    fn string(&mut self, buf: Vec<u8>) -> Event {
+        if let Some(Container::Dict(key @ None)) = self.stack.last_mut() {
+            *key = Some(buf.clone());
+            return Event::Key(buf);
+        }
+        self.finish_value();
+        Event::Bytes(buf)
+    }
+
+    /// Move on from a complete value to whatever follows it.
+    fn finish_value(&mut self) {
+        match self.stack.last_mut() {
+            None => self.state = State::Done,
+            Some(Container::List(index)) => *index += 1,
+            Some(Container::Dict(key)) => *key = None,
+        }
+    }
+
+    /// The path to the value being parsed, in the form `info.files[3].path`.
+    fn path(&self) -> String {
+        path(self.stack.iter().filter_map(|container| match container {
+            Container::List(index) => Some(Segment::Index(*index)),
+            Container::Dict(key) => key.as_deref().map(Segment::Key),
+        }))
+    }
+
+    /// Note that the input has ended, which is an error unless the value is done.
+    pub fn end_of_input(&self) -> Result<(), BencodeError> {
+        if self.is_done() {
+            return Ok(());
+        }
+        Err(BencodeError::UnexpectedEof {
+            offset: self.offset,
+            path: self.path(),
+        })
+    }
+
+    /// Read the next event from `reader`, or `None` once the value is done.
+    ///
+    /// Nothing past the end of the value is consumed.
+    pub async fn next_event<R>(&mut self, reader: &mut R) -> anyhow::Result<Option<Event>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        while !self.is_done() {
+            let input = reader.fill_buf().await?;
+            if input.is_empty() {
+                self.end_of_input()?;
+            }
+            let (consumed, event) = self.parse(input)?;
+            reader.consume(consumed);
+            if event.is_some() {
+                return Ok(event);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Read a single value from `reader`, returning it still encoded, ready for
+/// [`decode`](super::decode).
+///
+/// The value is checked as it arrives, so input which isn't bencode fails as soon as that
+/// becomes clear, and nothing past the end of the value is consumed.
+pub async fn read_value<R>(reader: &mut R) -> anyhow::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut parser = StreamParser::new();
+    let mut value = Vec::new();
+    while !parser.is_done() {
+        let input = reader.fill_buf().await?;
+        if input.is_empty() {
+            parser.end_of_input()?;
+        }
+        let (consumed, _) = parser.parse(input)?;
+        value.extend_from_slice(&input[..consumed]);
+        reader.consume(consumed);
+    }
+    Ok(value)
+}
@@ -0,0 +1,514 @@
+//! An owned bencode value, for building and editing rather than just reading.
+
+use std::{collections::BTreeMap, fmt::Display, io::Write};
+
+use serde::{
+    de::{MapAccess, SeqAccess, Unexpected, Visitor},
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize,
+};
+use tokio::io::AsyncBufRead;
+
+use super::{BencodeError, Decoded, DecodedKind, Event, StreamParser};
+
+/// A bencoded value which owns its data.
+///
+/// Unlike [`Decoded`], strings are always bytes, with no guess at whether they are text.
+/// Dictionaries are kept sorted by key, so encoding is canonical.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// An empty list.
+    pub fn list() -> Self {
+        Value::List(Vec::new())
+    }
+
+    /// An empty dictionary.
+    pub fn dict() -> Self {
+        Value::Dict(BTreeMap::new())
+    }
+
+    /// Decode the single value making up the whole of `encoded`.
+    ///
+    /// Anything non-canonical is accepted, with the last of any duplicate keys winning.
+    pub fn decode(encoded: &[u8]) -> Result<Self, BencodeError> {
+        let mut parser = StreamParser::new();
+        let mut builder = Builder::default();
+        let mut rest = encoded;
+        loop {
+            if rest.is_empty() {
+                parser.end_of_input()?;
+            }
+            let (consumed, event) = parser.parse(rest)?;
+            rest = &rest[consumed..];
+            if let Some(value) = event.and_then(|event| builder.push(event)) {
+                if !rest.is_empty() {
+                    return Err(BencodeError::TrailingData {
+                        offset: encoded.len() - rest.len(),
+                    });
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Read a single value from `reader`, without consuming anything past its end.
+    pub async fn read<R>(reader: &mut R) -> anyhow::Result<Self>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut parser = StreamParser::new();
+        let mut builder = Builder::default();
+        while let Some(event) = parser.next_event(reader).await? {
+            if let Some(value) = builder.push(event) {
+                return Ok(value);
+            }
+        }
+        unreachable!("the parser finishes with the value")
+    }
+
+    pub fn encode<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Value::Bytes(b) => {
+                write!(writer, "{}:", b.len())?;
+                writer.write_all(b)?;
+            }
+            Value::Int(n) => write!(writer, "i{}e", n)?,
+            Value::List(l) => {
+                write!(writer, "l")?;
+                for v in l {
+                    v.encode(writer)?;
+                }
+                write!(writer, "e")?;
+            }
+            Value::Dict(d) => {
+                write!(writer, "d")?;
+                for (k, v) in d {
+                    write!(writer, "{}:", k.len())?;
+                    writer.write_all(k)?;
+                    v.encode(writer)?;
+                }
+                write!(writer, "e")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `key` to a dictionary, for building one up in a single expression.
+    ///
+    /// # Panics
+    ///
+    /// If `self` is not a dictionary.
+    pub fn with(mut self, key: impl Into<Vec<u8>>, value: impl Into<Value>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Set `key` of a dictionary, returning the value it replaced.
+    ///
+    /// # Panics
+    ///
+    /// If `self` is not a dictionary.
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Value>) -> Option<Value> {
+        self.as_dict_mut()
+            .expect("inserting into a value which is not a dictionary")
+            .insert(key.into(), value.into())
+    }
+
+    /// Remove `key` from a dictionary, returning its value if it was there.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<Value> {
+        self.as_dict_mut()?.remove(key.as_ref())
+    }
+
+    /// The value of `key`, if `self` is a dictionary holding it.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Value> {
+        self.as_dict()?.get(key.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut Value> {
+        self.as_dict_mut()?.get_mut(key.as_ref())
+    }
+
+    /// The value at `path`, which is made of dictionary keys and list indices separated by dots,
+    /// such as `info.files.0.length`.
+    ///
+    /// Keys holding dots can only be reached with [`get`](Self::get).
+    pub fn get_path(&self, path: &str) -> Option<&Value> {
+        path.split('.')
+            .try_fold(self, |value, segment| match value {
+                Value::Dict(d) => d.get(segment.as_bytes()),
+                Value::List(l) => l.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut Value> {
+        path.split('.')
+            .try_fold(self, |value, segment| match value {
+                Value::Dict(d) => d.get_mut(segment.as_bytes()),
+                Value::List(l) => l.get_mut(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The string, if this is a byte string holding valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+/// Assembles a value from the events of a [`StreamParser`].
+#[derive(Debug, Default)]
+struct Builder {
+    /// The containers being filled, each with the key its next value goes under
+    stack: Vec<(Value, Option<Vec<u8>>)>,
+}
+
+impl Builder {
+    /// Add `event`, returning the value once it is complete.
+    fn push(&mut self, event: Event) -> Option<Value> {
+        let value = match event {
+            Event::Int(n) => Value::Int(n),
+            Event::Bytes(b) => Value::Bytes(b),
+            Event::Key(key) => {
+                let (_, next) = self.stack.last_mut().expect("keys are inside dictionaries");
+                *next = Some(key);
+                return None;
+            }
+            Event::ListStart => {
+                self.stack.push((Value::list(), None));
+                return None;
+            }
+            Event::DictStart => {
+                self.stack.push((Value::dict(), None));
+                return None;
+            }
+            Event::End => self.stack.pop().expect("ends match starts").0,
+        };
+        match self.stack.last_mut() {
+            None => Some(value),
+            Some((Value::List(list), _)) => {
+                list.push(value);
+                None
+            }
+            Some((Value::Dict(dict), key)) => {
+                dict.insert(key.take().expect("values follow keys"), value);
+                None
+            }
+            Some(_) => unreachable!("only containers are on the stack"),
+        }
+    }
+}
+
+impl From<&Decoded<'_>> for Value {
+    fn from(value: &Decoded<'_>) -> Self {
+        match &value.kind {
+            DecodedKind::Bytes(b) => Value::Bytes(b.to_vec()),
+            DecodedKind::String(s) => Value::Bytes(s.as_bytes().to_vec()),
+            DecodedKind::Int(n) => Value::Int(*n),
+            DecodedKind::List(l) => Value::List(l.iter().map(Value::from).collect()),
+            DecodedKind::Dict(d) => Value::Dict(
+                d.iter()
+                    .map(|(k, v)| (k.to_vec(), Value::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Borrow a value as [`Decoded`], to deserialize it or pass it to code expecting one.
+impl<'a> From<&'a Value> for Decoded<'a> {
+    fn from(value: &'a Value) -> Self {
+        let kind = match value {
+            Value::Bytes(b) => match std::str::from_utf8(b) {
+                Ok(s) => DecodedKind::String(s),
+                Err(_) => DecodedKind::Bytes(b),
+            },
+            Value::Int(n) => DecodedKind::Int(*n),
+            Value::List(l) => DecodedKind::List(l.iter().map(Decoded::from).collect()),
+            Value::Dict(d) => {
+                DecodedKind::Dict(d.iter().map(|(k, v)| (&k[..], Decoded::from(v))).collect())
+            }
+        };
+        Decoded { source: None, kind }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<u16> for Value {
+    fn from(n: u16) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<u8> for Value {
+    fn from(n: u8) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Int(b.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Self {
+        Value::List(l)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, Value>> for Value {
+    fn from(d: BTreeMap<Vec<u8>, Value>) -> Self {
+        Value::Dict(d)
+    }
+}
+
+/// Collects a list.
+impl FromIterator<Value> for Value {
+    fn from_iter<I: IntoIterator<Item = Value>>(iter: I) -> Self {
+        Value::List(iter.into_iter().collect())
+    }
+}
+
+/// Collects a dictionary.
+impl<K, V> FromIterator<(K, V)> for Value
+where
+    K: Into<Vec<u8>>,
+    V: Into<Value>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Value::Dict(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // byte strings are shown as text where they can be, as with `Decoded`
+        fn bytes(f: &mut std::fmt::Formatter<'_>, b: &[u8]) -> std::fmt::Result {
+            match std::str::from_utf8(b) {
+                Ok(s) => write!(f, "{}", s),
+                Err(_) => {
+                    write!(f, "0x")?;
+                    b.iter().try_for_each(|b| write!(f, "{:02x}", b))
+                }
+            }
+        }
+
+        match self {
+            Value::Bytes(b) => bytes(f, b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::List(l) => {
+                write!(f, "[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Dict(d) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in d.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    bytes(f, k)?;
+                    write!(f, ": {}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Value::Bytes(b) => s.serialize_bytes(b),
+            Value::Int(n) => s.serialize_i64(*n),
+            Value::List(list) => {
+                let mut seq = s.serialize_seq(Some(list.len()))?;
+                for value in list {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Dict(dict) => {
+                let mut map = s.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(serde_bytes::Bytes::new(key), value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a bencode value")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Value, E> {
+                Ok(v.into())
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Value, E> {
+                Ok(Value::Int(v))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Value, E> {
+                i64::try_from(v)
+                    .map(Value::Int)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Value, E> {
+                Ok(v.into())
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Value, E> {
+                Ok(v.into())
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+                Ok(v.into())
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(Value::List(list))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut dict = BTreeMap::new();
+                while let Some((key, value)) = map.next_entry::<serde_bytes::ByteBuf, _>()? {
+                    dict.insert(key.into_vec(), value);
+                }
+                Ok(Value::Dict(dict))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
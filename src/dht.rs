@@ -0,0 +1,429 @@
+//! A mainline DHT node (BEP 5), for finding peers without a tracker.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::JoinSet,
+    time::timeout,
+};
+
+mod krpc;
+mod routing;
+
+use krpc::{Body, Message, Query, Response, PROTOCOL_ERROR};
+pub use krpc::{NodeId, NodeInfo};
+use routing::{distance, RoutingTable, K};
+
+/// How long a node has to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of queries a lookup keeps in flight at once
+const ALPHA: usize = 3;
+
+/// How often the secret behind announce tokens changes. Tokens from the previous secret are
+/// still accepted, so a token is good for between one and two rotations.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long a peer announced to us is handed out before it has to announce again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Most peers returned for a single `get_peers` query, keeping the response within one packet
+const MAX_VALUES: usize = 50;
+
+#[derive(Debug)]
+struct Secrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+type PendingQuery = (SocketAddr, oneshot::Sender<Body>);
+
+/// The result of an iterative lookup.
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: HashSet<SocketAddr>,
+    /// The closest nodes which answered, with the token each gave us
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+/// A DHT node listening on a UDP socket.
+///
+/// [`Dht::run`] has to be running for queries to get answers.
+#[derive(Debug)]
+pub struct Dht {
+    socket: UdpSocket,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    /// Queries awaiting a response by transaction id, with the node they were sent to
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+    secrets: Mutex<Secrets>,
+    /// Peers which announced themselves to us, by info hash
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+}
+
+impl Dht {
+    /// Listen on `addr` with a random node id.
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("binding DHT socket to {}", addr))?;
+        let mut rng = rand::thread_rng();
+        let id = rng.gen();
+        Ok(Arc::new(Self {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::default(),
+            next_transaction: AtomicU16::new(rng.gen()),
+            secrets: Mutex::new(Secrets {
+                current: rng.gen(),
+                previous: rng.gen(),
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::default(),
+        }))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Answer queries and dispatch responses forever.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let (len, from) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .context("receiving DHT packet")?;
+            // anyone can send us anything, so malformed packets are dropped without comment
+            let Ok(message) = Message::decode(&buf[..len]) else {
+                continue;
+            };
+            match message.body {
+                Body::Query { id, query } => {
+                    self.table
+                        .lock()
+                        .unwrap()
+                        .insert(NodeInfo { id, addr: from });
+                    let response = Message {
+                        transaction: message.transaction,
+                        body: self.respond(from, query),
+                    };
+                    // a node we can't reply to is no different from one that went away
+                    let _ = self.socket.send_to(&response.encode(), from).await;
+                }
+                body => {
+                    let mut pending = self.pending.lock().unwrap();
+                    if pending
+                        .get(&message.transaction)
+                        .is_some_and(|(addr, _)| *addr == from)
+                    {
+                        let (_, tx) = pending.remove(&message.transaction).unwrap();
+                        let _ = tx.send(body);
+                    }
+                }
+            }
+        }
+    }
+
+    fn respond(&self, from: SocketAddr, query: Query) -> Body {
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.token(from.ip()));
+                let mut peers = self.peers.lock().unwrap();
+                if let Some(peers) = peers.get_mut(&info_hash) {
+                    peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                    response.values = peers.keys().copied().take(MAX_VALUES).collect();
+                }
+                if response.values.is_empty() {
+                    response.nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.valid_token(from.ip(), &token) {
+                    return Body::Error {
+                        code: PROTOCOL_ERROR,
+                        message: "bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(SocketAddr::new(from.ip(), port), Instant::now());
+            }
+        }
+        Body::Response(response)
+    }
+
+    /// The token a node at `ip` has to present to announce to us.
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated = Instant::now();
+        }
+        token(&secrets.current, ip)
+    }
+
+    fn valid_token(&self, ip: IpAddr, token: &[u8]) -> bool {
+        let secrets = self.secrets.lock().unwrap();
+        token == self::token(&secrets.current, ip) || token == self::token(&secrets.previous, ip)
+    }
+
+    /// Send `query` to the node at `addr` and wait for its response.
+    async fn query(&self, addr: SocketAddr, query: Query) -> anyhow::Result<Response> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), (addr, tx));
+        let message = Message {
+            transaction: transaction.clone(),
+            body: Body::Query { id: self.id, query },
+        };
+
+        let res = async {
+            self.socket
+                .send_to(&message.encode(), addr)
+                .await
+                .context("sending DHT query")?;
+            timeout(QUERY_TIMEOUT, rx)
+                .await
+                .context("timed out waiting for DHT response")?
+                .context("DHT node went away")
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&transaction);
+
+        match res {
+            Ok(Body::Response(response)) => {
+                self.table.lock().unwrap().insert(NodeInfo {
+                    id: response.id,
+                    addr,
+                });
+                Ok(response)
+            }
+            Ok(Body::Error { code, message }) => {
+                bail!("DHT node {} returned error {}: {}", addr, code, message)
+            }
+            Ok(Body::Query { .. }) => unreachable!("queries are never dispatched as responses"),
+            Err(e) => {
+                self.table.lock().unwrap().failed(addr);
+                Err(e)
+            }
+        }
+    }
+
+    /// Check that the node at `addr` is alive, returning its id.
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Join the DHT through `nodes`, given as `host:port`, and fill the routing table with the
+    /// nodes closest to us.
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[String]) -> anyhow::Result<()> {
+        for node in nodes {
+            let addrs = match lookup_host(node).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    eprintln!("resolving DHT node {}: {}", node, e);
+                    continue;
+                }
+            };
+            for addr in addrs.filter(SocketAddr::is_ipv4) {
+                let target = self.id;
+                if let Err(e) = self.query(addr, Query::FindNode { target }).await {
+                    eprintln!("bootstrapping from DHT node {}: {:#}", addr, e);
+                }
+            }
+        }
+        ensure!(self.node_count() > 0, "no DHT bootstrap node answered");
+        self.lookup(self.id, false).await;
+        Ok(())
+    }
+
+    /// Find peers for the torrent with `info_hash`.
+    pub async fn get_peers(self: &Arc<Self>, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true)
+            .await
+            .peers
+            .into_iter()
+            .collect()
+    }
+
+    /// Find peers for the torrent with `info_hash`, and tell the closest nodes that we accept
+    /// connections for it on `port`.
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        let mut set = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let dht = Arc::clone(self);
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token,
+            };
+            set.spawn(async move {
+                if let Err(e) = dht.query(node.addr, query).await {
+                    eprintln!("announcing to DHT node {}: {:#}", node.addr, e);
+                }
+            });
+        }
+        set.join_all().await;
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Iteratively query the nodes closest to `target`, with `get_peers` queries if
+    /// `get_peers` is set or `find_node` otherwise, until no closer nodes turn up.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut lookup = Lookup::default();
+
+        let mut set = JoinSet::new();
+        loop {
+            while set.len() < ALPHA {
+                let Some((&d, &node)) = candidates
+                    .iter()
+                    .find(|(_, node)| !queried.contains(&node.addr))
+                else {
+                    break;
+                };
+                // once K closer nodes have answered, querying further away won't find anything
+                if responded.keys().nth(K - 1).is_some_and(|&kth| kth < d) {
+                    break;
+                }
+                queried.insert(node.addr);
+                let query = if get_peers {
+                    Query::GetPeers { info_hash: target }
+                } else {
+                    Query::FindNode { target }
+                };
+                let dht = Arc::clone(self);
+                set.spawn(async move { (node.addr, dht.query(node.addr, query).await) });
+            }
+
+            let Some(res) = set.join_next().await else {
+                break;
+            };
+            let Ok((addr, Ok(response))) = res else {
+                continue;
+            };
+            lookup.peers.extend(response.values);
+            for node in response.nodes {
+                if node.id != self.id {
+                    candidates.insert(distance(&node.id, &target), node);
+                }
+            }
+            let node = NodeInfo {
+                id: response.id,
+                addr,
+            };
+            responded.insert(distance(&node.id, &target), (node, response.token));
+        }
+
+        lookup.closest = responded.into_values().take(K).collect();
+        lookup
+    }
+}
+
+fn token(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// A node listening on a free loopback port.
+    async fn node() -> Arc<Dht> {
+        let dht = Dht::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let run = Arc::clone(&dht);
+        tokio::spawn(async move { run.run().await });
+        dht
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found_by_other_nodes() {
+        let (a, b, c) = (node().await, node().await, node().await);
+        let bootstrap = [b.local_addr().unwrap().to_string()];
+        a.bootstrap(&bootstrap).await.unwrap();
+        c.bootstrap(&bootstrap).await.unwrap();
+        assert_eq!(b.node_count(), 2);
+
+        let info_hash = [7; 20];
+        assert!(a.announce(info_hash, 6881).await.is_empty());
+        let peers = c.get_peers(info_hash).await;
+        assert_eq!(peers, [SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))]);
+    }
+}
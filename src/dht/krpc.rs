@@ -0,0 +1,354 @@
+//! KRPC, the bencoded query/response protocol DHT nodes speak over UDP.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context};
+
+use crate::decode::{decode, Decoded, Value};
+
+/// Node ids share the 160 bit space of info hashes
+pub type NodeId = [u8; 20];
+
+/// Error code for malformed queries
+pub const PROTOCOL_ERROR: i64 = 203;
+
+/// A node's id and address, as found in compact node lists.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        /// Use the port the query was sent from instead of `port`
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The `r` dictionary of a response. Which fields are set depends on the query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    /// Peers for the info hash of a `get_peers` query
+    pub values: Vec<SocketAddr>,
+    /// Token which must be presented to announce to the responding node
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A single KRPC message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Chosen by the querying node and echoed back in the response
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = Value::dict().with("t", self.transaction.clone());
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = Value::dict().with("id", &id[..]);
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => {
+                        args.insert("target", &target[..]);
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert("info_hash", &info_hash[..]);
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.insert("info_hash", &info_hash[..]);
+                        args.insert("port", *port);
+                        args.insert("implied_port", *implied_port);
+                        args.insert("token", token.clone());
+                    }
+                }
+                message.insert("y", "q");
+                message.insert("q", query.method());
+                message.insert("a", args);
+            }
+            Body::Response(response) => {
+                let mut r = Value::dict().with("id", &response.id[..]);
+                // BEP 5 only has room for IPv4 nodes and peers in compact form
+                let nodes: Vec<u8> = response
+                    .nodes
+                    .iter()
+                    .filter_map(|node| {
+                        let mut buf = node.id.to_vec();
+                        compact(node.addr, &mut buf)?;
+                        Some(buf)
+                    })
+                    .collect::<Vec<_>>()
+                    .concat();
+                let values: Vec<Value> = response
+                    .values
+                    .iter()
+                    .filter_map(|&addr| {
+                        let mut buf = Vec::new();
+                        compact(addr, &mut buf)?;
+                        Some(Value::Bytes(buf))
+                    })
+                    .collect();
+                if !response.nodes.is_empty() {
+                    r.insert("nodes", nodes);
+                }
+                if !values.is_empty() {
+                    r.insert("values", values);
+                }
+                if let Some(token) = &response.token {
+                    r.insert("token", token.clone());
+                }
+                message.insert("y", "r");
+                message.insert("r", r);
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                message.insert("y", "e");
+                message.insert("e", vec![Value::Int(*code), text.as_str().into()]);
+            }
+        }
+
+        let mut buf = Vec::new();
+        message
+            .encode(&mut buf)
+            .expect("writing to a Vec can't fail");
+        buf
+    }
+
+    pub fn decode(packet: &[u8]) -> anyhow::Result<Self> {
+        let (_, value) = decode(packet).context("decoding KRPC message")?;
+        if value.as_dict().is_none() {
+            bail!("KRPC message is not a dictionary");
+        }
+        let transaction = value
+            .get("t")
+            .and_then(Decoded::as_bytes)
+            .context("KRPC message has no transaction id")?
+            .to_vec();
+        let kind = value
+            .get("y")
+            .and_then(Decoded::as_bytes)
+            .context("KRPC message has no type")?;
+
+        let body = match kind {
+            b"q" => {
+                let method = value
+                    .get("q")
+                    .and_then(Decoded::as_bytes)
+                    .context("query has no method")?;
+                let args = value
+                    .get("a")
+                    .filter(|args| args.as_dict().is_some())
+                    .context("query has no arguments")?;
+                let id = node_id(args, "id")?;
+                let query = match method {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: node_id(args, "target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: node_id(args, "info_hash")?,
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: node_id(args, "info_hash")?,
+                        port: args
+                            .get("port")
+                            .and_then(Decoded::as_int)
+                            .and_then(|port| u16::try_from(port).ok())
+                            .context("announce has no port")?,
+                        implied_port: args
+                            .get("implied_port")
+                            .and_then(Decoded::as_int)
+                            .unwrap_or(0)
+                            != 0,
+                        token: args
+                            .get("token")
+                            .and_then(Decoded::as_bytes)
+                            .context("announce has no token")?
+                            .to_vec(),
+                    },
+                    method => bail!("unknown KRPC method {:?}", String::from_utf8_lossy(method)),
+                };
+                Body::Query { id, query }
+            }
+            b"r" => {
+                let r = value
+                    .get("r")
+                    .filter(|r| r.as_dict().is_some())
+                    .context("response has no values")?;
+                let nodes = r
+                    .get("nodes")
+                    .and_then(Decoded::as_bytes)
+                    .unwrap_or_default();
+                let values = r
+                    .get("values")
+                    .and_then(Decoded::as_list)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Decoded::as_bytes)
+                    .filter_map(|peer| parse_peers(peer).next())
+                    .collect();
+                Body::Response(Response {
+                    id: node_id(r, "id")?,
+                    nodes: nodes
+                        .chunks_exact(26)
+                        .map(|chunk| {
+                            let (id, addr) = chunk.split_at(20);
+                            NodeInfo {
+                                id: id.try_into().unwrap(),
+                                addr: parse_peers(addr).next().unwrap(),
+                            }
+                        })
+                        .collect(),
+                    values,
+                    token: r
+                        .get("token")
+                        .and_then(Decoded::as_bytes)
+                        .map(|t| t.to_vec()),
+                })
+            }
+            b"e" => {
+                let error = value.get("e").and_then(Decoded::as_list);
+                let field = |i| error.and_then(|e| e.get(i));
+                let (code, message) = (
+                    field(0).and_then(Decoded::as_int),
+                    field(1).and_then(Decoded::as_bytes),
+                );
+                Body::Error {
+                    code: code.unwrap_or_default(),
+                    message: String::from_utf8_lossy(message.unwrap_or_default()).into_owned(),
+                }
+            }
+            kind => bail!(
+                "unknown KRPC message type {:?}",
+                String::from_utf8_lossy(kind)
+            ),
+        };
+        Ok(Self { transaction, body })
+    }
+}
+
+/// Append `addr` in compact form, or return `None` for IPv6 addresses which don't fit.
+fn compact(addr: SocketAddr, buf: &mut Vec<u8>) -> Option<()> {
+    let IpAddr::V4(ip) = addr.ip() else {
+        return None;
+    };
+    buf.extend_from_slice(&ip.octets());
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    Some(())
+}
+
+fn parse_peers(list: &[u8]) -> impl Iterator<Item = SocketAddr> + '_ {
+    list.chunks_exact(6).map(|chunk| {
+        let (ip, port) = chunk.split_at(4);
+        let ip: [u8; 4] = ip.try_into().unwrap();
+        SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::from(ip)),
+            u16::from_be_bytes(port.try_into().unwrap()),
+        )
+    })
+}
+
+fn node_id(dict: &Decoded<'_>, key: &str) -> anyhow::Result<NodeId> {
+    dict.get(key)
+        .and_then(Decoded::as_bytes)
+        .and_then(|id| id.try_into().ok())
+        .with_context(|| format!("missing or invalid `{}`", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let id = [1; 20];
+        let node = NodeInfo {
+            id: [2; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        let queries = [
+            Query::Ping,
+            Query::FindNode { target: [3; 20] },
+            Query::GetPeers { info_hash: [4; 20] },
+            Query::AnnouncePeer {
+                info_hash: [4; 20],
+                port: 6881,
+                implied_port: true,
+                token: b"token".to_vec(),
+            },
+        ];
+        let bodies = queries
+            .into_iter()
+            .map(|query| Body::Query { id, query })
+            .chain([
+                Body::Response(Response {
+                    id,
+                    ..Default::default()
+                }),
+                Body::Response(Response {
+                    id,
+                    nodes: vec![node, node],
+                    values: vec!["10.0.0.2:51413".parse().unwrap()],
+                    token: Some(b"token".to_vec()),
+                }),
+                Body::Error {
+                    code: PROTOCOL_ERROR,
+                    message: "bad token".to_string(),
+                },
+            ]);
+        for body in bodies {
+            let message = Message {
+                transaction: b"aa".to_vec(),
+                body,
+            };
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn queries_need_arguments() {
+        assert!(Message::decode(b"d1:q4:ping1:t2:aa1:y1:qe").is_err());
+        assert!(Message::decode(b"d1:t2:aa1:y1:qe").is_err());
+        assert!(Message::decode(b"le").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! The Kademlia routing table: known nodes sorted into buckets by their distance from us.
+
+use std::net::SocketAddr;
+
+use super::krpc::{NodeId, NodeInfo};
+
+/// Nodes kept per bucket
+pub const K: usize = 8;
+
+/// Failed queries after which a node is considered bad and may be replaced
+const MAX_FAILURES: u32 = 2;
+
+/// XOR distance between two ids, which compares in the same order as the number it represents.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    /// Queries the node failed to answer since it last did
+    failures: u32,
+}
+
+/// One bucket per bit of distance, where bucket `i` holds nodes whose distance from us has its
+/// highest set bit at position `i`.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// The bucket `id` belongs in, or `None` for our own id.
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let zeros = distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|byte| byte * 8 + distance[byte].leading_zeros() as usize)?;
+        Some(159 - zeros)
+    }
+
+    /// Record that `node` is alive, adding it if its bucket has room or holds a bad node.
+    pub fn insert(&mut self, node: NodeInfo) {
+        let Some(index) = self.bucket(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        let entry = Entry { node, failures: 0 };
+        if let Some(existing) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+            *existing = entry;
+        } else if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(bad) = bucket.iter_mut().find(|e| e.failures >= MAX_FAILURES) {
+            *bad = entry;
+        }
+    }
+
+    /// Record that the node at `addr` didn't answer a query.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(entry) = bucket.iter_mut().find(|e| e.node.addr == addr) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `count` good nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.failures < MAX_FAILURES)
+            .map(|e| e.node)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decode::{decode, encode, from_decoded},
+    peer::Message,
+};
+
+/// Set in `reserved[5]` of the handshake by peers which support the extension protocol (BEP 10)
+pub const RESERVED_BIT: u8 = 0x10;
+
+/// Extended message id of the extension handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Client name and version sent as `v`
+pub const CLIENT_VERSION: &str = concat!("bittorrent-starter-rust ", env!("CARGO_PKG_VERSION"));
+
+/// Number of outstanding requests we advertise as `reqq`. Requests are served as soon as they
+/// arrive, so this only bounds how far ahead a peer may pipeline.
+const REQUEST_QUEUE: u32 = 250;
+
+/// The extension handshake, sent as extended message 0 straight after the regular handshake.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender wants to receive them with, where 0
+    /// means the extension is not supported
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The port the sender accepts connections on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// The sender's client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// How many outstanding requests the sender will queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// Size of the info dictionary, for peers which can serve it with `ut_metadata`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(payload: &[u8]) -> anyhow::Result<Self> {
+        let (_, value) = decode(payload).context("decoding extension handshake")?;
+        from_decoded(&value).context("parsing extension handshake")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        encode(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// The id the peer wants to receive extension `name` with, if it supports it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id != 0)
+    }
+}
+
+/// A handler for one extension, with its own state for a single peer connection.
+pub trait Extension: Send + Sync {
+    /// The name the extension is advertised under in `m`
+    fn name(&self) -> &'static str;
+
+    /// Fill in any fields of our extension handshake this extension is responsible for.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called once the peer's extension handshake arrives.
+    fn on_handshake(&mut self, _theirs: &ExtendedHandshake) {}
+
+    /// Handle a message the peer sent for this extension, returning the payloads of any
+    /// messages to send back.
+    fn handle(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// The payloads of any messages to send unprompted, checked regularly once the peer has said
+    /// it supports the extension.
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// The extensions enabled on a connection, which dispatches extended messages to them.
+///
+/// Extensions receive messages on the id matching their position in the registry, starting at
+/// 1.
+#[derive(Default)]
+pub struct Registry {
+    handlers: Vec<Box<dyn Extension>>,
+    port: Option<u16>,
+    theirs: Option<ExtendedHandshake>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field(
+                "handlers",
+                &self.handlers.iter().map(|h| h.name()).collect::<Vec<_>>(),
+            )
+            .field("port", &self.port)
+            .field("theirs", &self.theirs)
+            .finish()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enable `extension` on the connection.
+    pub fn with(mut self, extension: impl Extension + 'static) -> Self {
+        self.handlers.push(Box::new(extension));
+        self
+    }
+
+    /// Advertise `port` as the one we accept connections on.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Our extension handshake.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            p: self.port,
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(REQUEST_QUEUE),
+            ..Default::default()
+        };
+        for (id, handler) in (1..).zip(&self.handlers) {
+            handshake.m.insert(handler.name().to_string(), id);
+            handler.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// The peer's extension handshake, once it has sent one
+    pub fn theirs(&self) -> Option<&ExtendedHandshake> {
+        self.theirs.as_ref()
+    }
+
+    /// Handle an extended message from the peer, returning any messages to send back.
+    ///
+    /// Messages for extensions we haven't enabled are ignored.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<Vec<Message>> {
+        if id == HANDSHAKE_ID {
+            let theirs = ExtendedHandshake::from_bytes(payload)?;
+            for handler in &mut self.handlers {
+                handler.on_handshake(&theirs);
+            }
+            self.theirs = Some(theirs);
+            return Ok(Vec::new());
+        }
+
+        let Some(handler) = self.handlers.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+        let replies = handler
+            .handle(payload)
+            .with_context(|| format!("handling {} message", handler.name()))?;
+        // a peer which sends us messages for an extension should also accept them
+        let Some(id) = self.theirs.as_ref().and_then(|t| t.id(handler.name())) else {
+            return Ok(Vec::new());
+        };
+        Ok(replies
+            .into_iter()
+            .map(|payload| Message::Extended { id, payload })
+            .collect())
+    }
+
+    /// Collect the messages extensions want to send unprompted.
+    pub fn poll(&mut self) -> Vec<Message> {
+        let Some(theirs) = &self.theirs else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        for handler in &mut self.handlers {
+            if let Some(id) = theirs.id(handler.name()) {
+                messages.extend(
+                    handler
+                        .poll()
+                        .into_iter()
+                        .map(|payload| Message::Extended { id, payload }),
+                );
+            }
+        }
+        messages
+    }
+}
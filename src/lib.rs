@@ -0,0 +1,227 @@
+use anyhow::Context;
+use bytes::Bytes;
+use decode::{decode, decode_checked, from_decoded, Mode};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+use tracker::{AnnounceRequest, TrackerList};
+
+pub mod bitfield;
+pub mod cli;
+pub mod create;
+pub mod decode;
+pub mod dht;
+pub mod extension;
+pub mod listener;
+pub mod magnet;
+pub mod peer;
+pub mod pex;
+pub mod resume;
+pub mod storage;
+pub mod swarm;
+pub mod tracker;
+pub mod verify;
+
+/// A tracker's response to an HTTP announce.
+///
+/// A tracker which refuses the announce may send nothing but `failure reason`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeersResponse {
+    /// Why the tracker refused the announce, in which case the other fields are meaningless
+    #[serde(default, rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    /// Something the tracker wants us to know even though the announce succeeded
+    #[serde(default, rename = "warning message")]
+    pub warning_message: Option<String>,
+    #[serde(default)]
+    pub interval: usize,
+    #[serde(default, rename = "min interval")]
+    pub min_interval: Option<usize>,
+    #[serde(default, rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    /// Number of seeders
+    #[serde(default)]
+    pub complete: Option<u32>,
+    /// Number of leechers
+    #[serde(default)]
+    pub incomplete: Option<u32>,
+    #[serde(default)]
+    pub peers: PeerList,
+    /// IPv6 peers, 18 bytes each (BEP 7)
+    #[serde(default, with = "serde_bytes")]
+    pub peers6: Vec<u8>,
+}
+
+/// Trackers send IPv4 peers either in compact form, 6 bytes each, or as a list of dictionaries.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    Dicts(Vec<PeerInfo>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(Vec::new())
+    }
+}
+
+/// A peer in a non-compact peer list.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerInfo {
+    #[serde(default, rename = "peer id", with = "serde_bytes")]
+    pub peer_id: Vec<u8>,
+    /// An IPv4 or IPv6 address, or a DNS name
+    pub ip: String,
+    pub port: u16,
+}
+
+impl PeersResponse {
+    /// Every peer in the response, from both `peers` and `peers6`.
+    ///
+    /// Peers given by DNS name rather than address are skipped.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = match &self.peers {
+            PeerList::Compact(list) => list
+                .chunks_exact(6)
+                .map(|chunk| {
+                    let (ip, port) = chunk.split_at(4);
+                    let [a, b, c, d] = ip.try_into().unwrap();
+                    SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                        u16::from_be_bytes(port.try_into().unwrap()),
+                    )
+                })
+                .collect(),
+            PeerList::Dicts(list) => list
+                .iter()
+                .filter_map(|peer| Some(SocketAddr::new(peer.ip.parse().ok()?, peer.port)))
+                .collect(),
+        };
+        peers.extend(self.peers6.chunks_exact(18).map(|chunk| {
+            let (ip, port) = chunk.split_at(16);
+            let ip: [u8; 16] = ip.try_into().unwrap();
+            SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                u16::from_be_bytes(port.try_into().unwrap()),
+            )
+        }));
+        peers
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TorrentInfo {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: u32,
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    #[serde(flatten)]
+    pub files: FileLayout,
+}
+
+/// The `info` dictionary either describes a single file (`length`) or a directory of files
+/// (`files`), never both.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum FileLayout {
+    Single { length: u64 },
+    Multi { files: Vec<TorrentFile> },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TorrentFile {
+    pub length: u64,
+    /// Path components relative to the torrent's root directory
+    pub path: Vec<String>,
+}
+
+impl TorrentInfo {
+    pub fn pieces(&self) -> impl Iterator<Item = &[u8]> {
+        self.pieces.chunks_exact(20)
+    }
+
+    /// Check `data` against the SHA-1 hash recorded for piece `index`.
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+        let Some(expected) = self.pieces().nth(index as usize) else {
+            return false;
+        };
+        let actual: [u8; 20] = Sha1::digest(data).into();
+        actual == expected
+    }
+
+    /// Total number of bytes across all files in the torrent
+    pub fn length(&self) -> u64 {
+        match &self.files {
+            FileLayout::Single { length } => *length,
+            FileLayout::Multi { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Torrent {
+    /// Tracker URL, which may be empty if the torrent has an `announce-list` instead
+    #[serde(default)]
+    pub announce: String,
+    /// Tiers of tracker URLs (BEP 12), which take precedence over `announce` when present
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Vec<Vec<String>>,
+    pub info: TorrentInfo,
+    /// The info dictionary exactly as it was encoded, for serving to peers with `ut_metadata`
+    #[serde(skip)]
+    pub info_bytes: Bytes,
+}
+
+impl Torrent {
+    /// Build a torrent from a raw info dictionary, such as one fetched for a magnet link.
+    pub fn from_info(announce: String, info: &[u8]) -> anyhow::Result<([u8; 20], Self)> {
+        let (_, value) = decode(info).context("decoding info")?;
+        let info_hash = Sha1::digest(info).into();
+        Ok((
+            info_hash,
+            Self {
+                announce,
+                announce_list: Vec::new(),
+                info: from_decoded(&value).context("parsing info")?,
+                info_bytes: Bytes::copy_from_slice(info),
+            },
+        ))
+    }
+
+    pub async fn read_file<P>(path: P) -> anyhow::Result<([u8; 20], Self)>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file = tokio::fs::read(path).await?;
+        // plenty of torrents in the wild aren't quite canonical
+        let (value, warnings) = decode_checked(&file, Mode::Lenient)?;
+        for warning in warnings {
+            eprintln!("warning: {}: {}", path.display(), warning);
+        }
+        let info = value
+            .get("info")
+            .and_then(|info| info.source)
+            .context("torrent has no info dictionary")?;
+        let mut torrent: Self = from_decoded(&value).context("parsing torrent")?;
+        torrent.info_bytes = Bytes::copy_from_slice(info);
+        Ok((Sha1::digest(info).into(), torrent))
+    }
+}
+
+/// Announce ourselves to the torrent's trackers as listening on `port` and return the peers the
+/// first one to respond knows of.
+pub async fn get_peers(
+    data: &Torrent,
+    info_hash: [u8; 20],
+    port: u16,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let request = AnnounceRequest::new(info_hash, port, data.info.length());
+    let response = TrackerList::new(data).announce(&request).await?;
+    Ok(response.peers)
+}
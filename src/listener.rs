@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{ensure, Context};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    peer::{peer_id, Handshake},
+    swarm::{Inbound, Swarm},
+};
+
+/// How long a peer has to send its handshake after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait after failing to accept a connection, such as when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections from other peers and hands each one to the swarm for the torrent named in
+/// its handshake.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    listener: Arc<TcpListener>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Inbound>>>,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("listening on {}", addr))?;
+        Ok(Self {
+            listener: Arc::new(listener),
+            torrents: Arc::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Start accepting peers for the torrent being downloaded by `swarm`.
+    pub fn register(&self, swarm: &Swarm) {
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(swarm.info_hash(), swarm.inbound());
+    }
+
+    /// Stop accepting peers for the torrent with `info_hash`.
+    pub fn unregister(&self, info_hash: [u8; 20]) {
+        self.torrents.lock().unwrap().remove(&info_hash);
+    }
+
+    /// Accept connections forever, handshaking with each in its own task.
+    pub async fn run(&self) {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("accepting peer: {}", e);
+                    sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let torrents = Arc::clone(&self.torrents);
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle(stream, &torrents).await {
+                    eprintln!("incoming peer {} dropped: {:#}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Respond to a peer's handshake if it is for one of our torrents.
+    async fn handle(
+        mut stream: TcpStream,
+        torrents: &Mutex<HashMap<[u8; 20], Inbound>>,
+    ) -> anyhow::Result<()> {
+        let theirs = timeout(HANDSHAKE_TIMEOUT, Handshake::read_from(&mut stream))
+            .await
+            .context("timed out waiting for handshake")??;
+        ensure!(theirs.peer_id != peer_id(), "connected to ourselves");
+        let inbound = torrents
+            .lock()
+            .unwrap()
+            .get(&theirs.info_hash)
+            .cloned()
+            .with_context(|| format!("unknown info hash {}", hex::encode(theirs.info_hash)))?;
+        inbound.accept(stream, theirs).await
+    }
+}
@@ -0,0 +1,360 @@
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+use anyhow::{bail, ensure, Context};
+use bytes::Bytes;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{
+    decode::{decode, encode, from_decoded},
+    extension::{self, ExtendedHandshake, Extension},
+    peer::{Handshake, Message},
+    tracker::{self, AnnounceRequest},
+    Torrent,
+};
+
+/// Name of the metadata exchange extension (BEP 9)
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The id we ask peers to send `ut_metadata` messages to us with
+const LOCAL_UT_METADATA_ID: u8 = 1;
+
+/// Metadata is exchanged in pieces of this size, with only the last one shorter
+const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// Refuse info dictionaries larger than this rather than allocate whatever a peer claims
+const MAX_METADATA_SIZE: usize = 1 << 24;
+
+/// How long a single peer gets to hand over the whole info dictionary
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A parsed `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`)
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`)
+    pub trackers: Vec<String>,
+    /// Peers to try directly (`x.pe`)
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).context("parsing magnet link")?;
+        ensure!(url.scheme() == "magnet", "not a magnet link");
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    // other exact topics (such as btmh for v2) can appear alongside
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => eprintln!("ignoring unparseable peer {:?} in magnet link", value),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.context("magnet link has no `urn:btih` info hash")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// Info hashes are either 40 hex digits or 32 base32 characters.
+fn parse_info_hash(s: &str) -> anyhow::Result<[u8; 20]> {
+    let mut hash = [0; 20];
+    match s.len() {
+        40 => hex::decode_to_slice(s, &mut hash).context("invalid hex info hash")?,
+        32 => {
+            let mut bits = 0u64;
+            let mut nbits = 0;
+            let mut out = hash.iter_mut();
+            for c in s.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => bail!("invalid base32 info hash"),
+                };
+                bits = (bits << 5) | value as u64;
+                nbits += 5;
+                if nbits >= 8 {
+                    nbits -= 8;
+                    *out.next()
+                        .expect("32 base32 characters are exactly 20 bytes") =
+                        (bits >> nbits) as u8;
+                }
+            }
+        }
+        n => bail!("info hash has {} characters, expected 40 or 32", n),
+    }
+    Ok(hash)
+}
+
+impl Magnet {
+    /// Find peers through the link's trackers and fetch the info dictionary from one of them.
+    ///
+    /// `port` is the port announced to the trackers.
+    pub async fn resolve(&self, port: u16) -> anyhow::Result<([u8; 20], Torrent)> {
+        let mut peers = self.peers.clone();
+        for url in &self.trackers {
+            // the length is unknown until we have the metadata, but we mustn't look like a seed
+            let request = AnnounceRequest::new(self.info_hash, port, 1);
+            match tracker::announce(url, &request).await {
+                Ok(response) => peers.extend(response.peers),
+                Err(e) => eprintln!("tracker {} failed: {:#}", url, e),
+            }
+        }
+        ensure!(!peers.is_empty(), "no peers found for magnet link");
+
+        for addr in peers {
+            match timeout(METADATA_TIMEOUT, fetch_metadata(addr, self.info_hash)).await {
+                Ok(Ok(info)) => {
+                    let announce = self.trackers.first().cloned().unwrap_or_default();
+                    let (info_hash, mut torrent) = Torrent::from_info(announce, &info)?;
+                    // each tracker gets a tier of its own, so they are all tried in order
+                    torrent.announce_list = self.trackers.iter().map(|t| vec![t.clone()]).collect();
+                    return Ok((info_hash, torrent));
+                }
+                Ok(Err(e)) => eprintln!("fetching metadata from {} failed: {:#}", addr, e),
+                Err(_) => eprintln!("fetching metadata from {} timed out", addr),
+            }
+        }
+        bail!("no peer could provide the metadata")
+    }
+}
+
+/// Serves our info dictionary to peers which ask for it with `ut_metadata`.
+#[derive(Debug, Clone)]
+pub struct MetadataServer {
+    info: Bytes,
+}
+
+impl MetadataServer {
+    /// Serve `info`, the encoded info dictionary of the torrent.
+    pub fn new(info: Bytes) -> Self {
+        Self { info }
+    }
+}
+
+impl Extension for MetadataServer {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.info.len());
+    }
+
+    fn handle(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let (_, header) = decode(payload).context("decoding ut_metadata message")?;
+        let request: MetadataMessage =
+            from_decoded(&header).context("parsing ut_metadata message")?;
+        if request.msg_type != 0 {
+            // we never request metadata on a connection where we serve it
+            return Ok(Vec::new());
+        }
+
+        let start = request.piece.saturating_mul(METADATA_PIECE_SIZE);
+        let mut reply = Vec::new();
+        if start >= self.info.len() {
+            let reject = MetadataMessage {
+                msg_type: 2,
+                piece: request.piece,
+                total_size: None,
+            };
+            encode(&mut reply, reject)?;
+            return Ok(vec![reply]);
+        }
+        let end = std::cmp::min(start + METADATA_PIECE_SIZE, self.info.len());
+        let data = MetadataMessage {
+            msg_type: 1,
+            piece: request.piece,
+            total_size: Some(self.info.len()),
+        };
+        encode(&mut reply, data)?;
+        reply.extend_from_slice(&self.info[start..end]);
+        Ok(vec![reply])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataMessage {
+    /// 0 for a request, 1 for data, 2 for a rejection
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Download the info dictionary for `info_hash` from the peer at `addr` using `ut_metadata`.
+///
+/// The result is checked against `info_hash` before being returned.
+pub async fn fetch_metadata(addr: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    Handshake::new(info_hash).write_to(&mut stream).await?;
+    let theirs = Handshake::read_from(&mut stream).await?;
+    ensure!(theirs.info_hash == info_hash, "Info hash not equal");
+    ensure!(
+        theirs.supports_extensions(),
+        "peer does not support extensions"
+    );
+
+    let ours = ExtendedHandshake {
+        m: [(UT_METADATA.to_string(), LOCAL_UT_METADATA_ID)].into(),
+        ..Default::default()
+    };
+    Message::Extended {
+        id: extension::HANDSHAKE_ID,
+        payload: ours.to_bytes()?,
+    }
+    .write_to(&mut stream)
+    .await?;
+
+    let theirs = loop {
+        if let Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload,
+        } = Message::read_from(&mut stream).await?
+        {
+            break ExtendedHandshake::from_bytes(&payload)?;
+        }
+    };
+    let id = theirs
+        .id(UT_METADATA)
+        .context("peer does not support ut_metadata")?;
+    let size = theirs
+        .metadata_size
+        .context("peer did not send metadata_size")?;
+    ensure!(
+        size > 0 && size <= MAX_METADATA_SIZE,
+        "metadata size {} is out of range",
+        size
+    );
+
+    let mut metadata = vec![0; size];
+    for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+        let mut payload = Vec::new();
+        encode(
+            &mut payload,
+            MetadataMessage {
+                msg_type: 0,
+                piece,
+                total_size: None,
+            },
+        )?;
+        Message::Extended { id, payload }
+            .write_to(&mut stream)
+            .await?;
+
+        let payload = loop {
+            match Message::read_from(&mut stream).await? {
+                Message::Extended {
+                    id: LOCAL_UT_METADATA_ID,
+                    payload,
+                } => break payload,
+                _ => continue,
+            }
+        };
+        let (data, header) = decode(&payload).context("decoding ut_metadata message")?;
+        let header: MetadataMessage =
+            from_decoded(&header).context("parsing ut_metadata message")?;
+        match header.msg_type {
+            1 => {}
+            2 => bail!("peer rejected request for metadata piece {}", piece),
+            t => bail!("unexpected ut_metadata message type {}", t),
+        }
+        ensure!(header.piece == piece, "got metadata piece {}", header.piece);
+
+        let start = piece * METADATA_PIECE_SIZE;
+        let end = std::cmp::min(start + METADATA_PIECE_SIZE, size);
+        ensure!(
+            data.len() == end - start,
+            "metadata piece {} has {} bytes, expected {}",
+            piece,
+            data.len(),
+            end - start
+        );
+        metadata[start..end].copy_from_slice(data);
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    ensure!(hash == info_hash, "metadata does not match info hash");
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [
+        0xd6, 0x9f, 0x91, 0xe6, 0xb2, 0xae, 0x4c, 0x54, 0x24, 0x68, 0xd1, 0x07, 0x3a, 0x71, 0xd4,
+        0xea, 0x13, 0x87, 0x9a, 0x7f,
+    ];
+
+    #[test]
+    fn links_with_hex_info_hashes_parse() {
+        let magnet: Magnet = "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f\
+            &dn=some%20file&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+            &tr=udp%3A%2F%2Ftracker.example%3A6969&x.pe=10.0.0.1%3A6881&x.pe=bogus"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: HASH,
+                name: Some("some file".to_string()),
+                trackers: vec![
+                    "http://tracker.example/announce".to_string(),
+                    "udp://tracker.example:6969".to_string(),
+                ],
+                peers: vec!["10.0.0.1:6881".parse().unwrap()],
+            }
+        );
+    }
+
+    #[test]
+    fn links_with_base32_info_hashes_parse() {
+        for hash in [
+            "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7",
+            "22pzdzvsvzgfijdi2edtu4ou5ijypgt7",
+        ] {
+            let magnet: Magnet = format!("magnet:?xt=urn:btih:{}", hash).parse().unwrap();
+            assert_eq!(magnet.info_hash, HASH);
+            assert_eq!(magnet.name, None);
+        }
+    }
+
+    #[test]
+    fn links_need_a_valid_info_hash() {
+        for link in [
+            "http://example.com/?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?dn=nothing",
+            "magnet:?xt=urn:btmh:1220d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7",
+            "magnet:?xt=urn:btih:z69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:12PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7",
+        ] {
+            assert!(link.parse::<Magnet>().is_err(), "{} was accepted", link);
+        }
+    }
+}
@@ -1,114 +1,101 @@
 use anyhow::Context;
+use bittorrent_starter_rust::{
+    bitfield::Bitfield,
+    cli::{Cli, SubCmd},
+    create::{create, CreateOptions},
+    decode::{decode_checked, Issue, Mode},
+    dht::Dht,
+    extension::{self, Registry},
+    get_peers,
+    listener::Listener,
+    magnet::Magnet,
+    peer::Client,
+    resume::ResumeFile,
+    storage::{Layout, PieceStore, Storage},
+    swarm::Swarm,
+    tracker::{self, Server, Session, TrackerList},
+    verify::{verify, PieceStatus},
+    Torrent,
+};
 use clap::Parser;
-use cli::{Cli, SubCmd};
-use core::str;
-use decode::{decode, Decoded};
-use peer::{Client, Piece};
-use reqwest::Url;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 use std::{
-    io::SeekFrom,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, SocketAddr},
     path::Path,
-    str::FromStr,
-};
-use tokio::{
-    fs::File,
-    io::{AsyncSeek, AsyncSeekExt, AsyncWriteExt},
-    sync::oneshot,
-    task::JoinSet,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-pub mod cli;
-pub mod decode;
-pub mod peer;
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct PeersResponse {
-    pub interval: usize,
-    pub peers: Vec<u8>,
-}
-
-impl PeersResponse {
-    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + use<'_> {
-        self.peers.chunks_exact(6).map(|chunk| {
-            let (ip, port) = chunk.split_at(4);
-            let [a, b, c, d] = ip.try_into().unwrap();
-            SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
-                u16::from_be_bytes(port.try_into().unwrap()),
-            )
-        })
+/// Load a torrent from a .torrent file, or resolve it if `source` is a magnet link, along with
+/// any peers the magnet link lists.
+async fn open_torrent(
+    source: &Path,
+    port: u16,
+    dht: Option<&Arc<Dht>>,
+) -> anyhow::Result<([u8; 20], Torrent, Vec<SocketAddr>)> {
+    match source.to_str() {
+        Some(link) if link.starts_with("magnet:") => {
+            let mut magnet: Magnet = link.parse()?;
+            if let Some(dht) = dht {
+                magnet.peers.extend(dht.get_peers(magnet.info_hash).await);
+            }
+            let (info_hash, data) = magnet.resolve(port).await?;
+            Ok((info_hash, data, magnet.peers))
+        }
+        _ => {
+            let (info_hash, data) = Torrent::read_file(source).await?;
+            Ok((info_hash, data, Vec::new()))
+        }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct TorrentInfo {
-    pub length: u32,
-    pub name: String,
-    #[serde(rename = "piece length")]
-    pub piece_length: u32,
-    pub pieces: Vec<u8>,
-}
-
-impl TorrentInfo {
-    fn pieces(&self) -> impl Iterator<Item = &[u8]> {
-        self.pieces.chunks_exact(20)
+fn mode(strict: bool) -> Mode {
+    if strict {
+        Mode::Strict
+    } else {
+        Mode::Lenient
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct Torrent {
-    pub announce: String,
-    pub info: TorrentInfo,
-}
-
-impl Torrent {
-    pub async fn read_file<P>(path: P) -> anyhow::Result<([u8; 20], Self)>
-    where
-        P: AsRef<Path>,
-    {
-        let file = tokio::fs::read(path).await?;
-        let (_, value) = decode(&file).unwrap();
-        let info_hash = get_info_hash(&value);
-        Ok((info_hash, serde(&value)?))
+/// Report what was wrong with leniently decoded input.
+fn warn(issues: &[Issue]) {
+    for issue in issues {
+        eprintln!("warning: {}", issue);
     }
 }
 
-pub fn serde<S, D>(s: &S) -> anyhow::Result<D>
-where
-    S: Serialize,
-    D: DeserializeOwned,
-{
-    Ok(
-        serde_json::from_str(&serde_json::to_string(s).context("serializing value")?)
-            .context("deserializing value")?,
-    )
-}
-
-async fn get_peers(data: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Vec<SocketAddr>> {
-    let mut url = Url::from_str(&data.announce)?;
-    url.query_pairs_mut()
-        .append_pair("info_hash", unsafe { str::from_utf8_unchecked(&info_hash) })
-        .append_pair("peer_id", "20 chars is too shor")
-        .append_pair("port", "6881")
-        .append_pair("uploaded", "0")
-        .append_pair("downloaded", "0")
-        .append_pair("left", &data.info.length.to_string())
-        .append_pair("compact", "1");
-    let res = reqwest::get(url).await?;
-    let text = res.bytes().await?;
-    let (_, res) = decode(&text).unwrap();
-    let res: PeersResponse = serde(&res)?;
-
-    Ok(res.peers().collect())
+/// Format piece indices as comma-separated ranges, such as `0-3, 7, 9-10`.
+fn ranges(indices: impl Iterator<Item = u32>) -> String {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for index in indices {
+        match runs.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => runs.push((index, index)),
+        }
+    }
+    runs.iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-fn get_info_hash(value: &Decoded<'_>) -> [u8; 20] {
-    let mut hasher = Sha1::new();
-    hasher.update(value["info"].source.unwrap());
-    hasher.finalize().into()
+/// Listen for DHT traffic on UDP `port` and join the DHT through `nodes`.
+async fn start_dht(port: u16, nodes: &[String]) -> anyhow::Result<Arc<Dht>> {
+    let dht = Dht::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await?;
+    let run = Arc::clone(&dht);
+    tokio::spawn(async move {
+        if let Err(e) = run.run().await {
+            eprintln!("DHT node stopped: {:#}", e);
+        }
+    });
+    dht.bootstrap(nodes).await?;
+    eprintln!("Joined the DHT with {} nodes", dht.node_count());
+    Ok(dht)
 }
 
 #[tokio::main]
@@ -116,23 +103,28 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.subcommand {
-        SubCmd::Decode { string } => {
-            let (_, value) = decode(&string.as_bytes()).unwrap();
+        SubCmd::Decode { string, strict } => {
+            let (value, warnings) = decode_checked(string.as_bytes(), mode(strict))?;
+            warn(&warnings);
             println!("{}", serde_json::to_string(&value)?);
             let mut vec = Vec::new();
             value.encode(&mut vec)?;
             eprintln!("{}", std::str::from_utf8(&vec)?);
         }
-        SubCmd::DecodeFile { torrent_file: path } => {
+        SubCmd::DecodeFile {
+            torrent_file: path,
+            strict,
+        } => {
             let file = tokio::fs::read(path).await?;
-            let (_, value) = decode(&file).unwrap();
+            let (value, warnings) = decode_checked(&file, mode(strict))?;
+            warn(&warnings);
             println!("{}", value);
         }
         SubCmd::Info { torrent_file } => {
             let (info_hash, data) = Torrent::read_file(torrent_file).await?;
 
             println!("Tracker URL: {}", data.announce);
-            println!("Length: {}", data.info.length);
+            println!("Length: {}", data.info.length());
             println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", data.info.piece_length);
             println!("Piece Hashes:");
@@ -144,7 +136,7 @@ async fn main() -> anyhow::Result<()> {
             let (info_hash, data) = Torrent::read_file(torrent_file).await?;
 
             eprintln!("Tracker URL: {}", data.announce);
-            eprintln!("Length: {}", data.info.length);
+            eprintln!("Length: {}", data.info.length());
             eprintln!("Info Hash: {}", hex::encode(info_hash));
             eprintln!("Piece Length: {}", data.info.piece_length);
             eprintln!("Piece Hashes:");
@@ -152,7 +144,7 @@ async fn main() -> anyhow::Result<()> {
                 eprintln!("{}", hex::encode(piece));
             }
 
-            let peers = get_peers(&data, info_hash.into()).await?;
+            let peers = get_peers(&data, info_hash, 6881).await?;
 
             for peer in peers {
                 println!("{}", peer);
@@ -165,8 +157,7 @@ async fn main() -> anyhow::Result<()> {
                 eprintln!("{}", hex::encode(piece));
             }
 
-            let handler = Client::connect(addr, data, info_hash);
-            // eprintln!("Peer ID: {}", hex::encode(peer_id));
+            let _handler = Client::connect(addr, data, info_hash, None, Registry::new()).await?;
         }
         SubCmd::DownloadPiece {
             out,
@@ -175,94 +166,296 @@ async fn main() -> anyhow::Result<()> {
         } => {
             let (info_hash, data) = Torrent::read_file(torrent_file).await?;
 
-            let piece_length = if data.info.pieces().count() as u32 - 1 == index {
-                data.info.length % data.info.piece_length
+            let layout = Layout::new(&data.info, &out)?;
+            anyhow::ensure!(
+                index < layout.piece_count(),
+                "piece {} out of range, torrent has {} pieces",
+                index,
+                layout.piece_count()
+            );
+            let piece_length = layout.piece_len(index);
+
+            let peers = get_peers(&data, info_hash, 6881).await?;
+            // let peer = peers[rand::thread_rng().gen_range(0..peers.len())];
+
+            let mut handler =
+                Client::connect(peers[0], data, info_hash, None, Registry::new()).await?;
+            handler.interested().await?;
+            handler.wait_unchoke().await?;
+
+            eprintln!("Requesting piece {} ({} bytes)", index, piece_length);
+            let Some(piece) = handler.download_piece(index, piece_length).await? else {
+                anyhow::bail!("choked while downloading piece {}", index);
+            };
+            anyhow::ensure!(
+                handler.torrent().info.verify_piece(index, &piece),
+                "piece {} failed hash check",
+                index
+            );
+            tokio::fs::write(&out, piece)
+                .await
+                .context("writing piece")?;
+        }
+        SubCmd::DownloadFile {
+            out,
+            torrent_file,
+            seed,
+            port,
+            dht_nodes,
+        } => {
+            let dht = if dht_nodes.is_empty() {
+                None
             } else {
-                data.info.piece_length
+                Some(start_dht(port, &dht_nodes).await?)
             };
+            let (info_hash, data, mut peers) =
+                open_torrent(&torrent_file, port, dht.as_ref()).await?;
 
-            let peers = get_peers(&data, info_hash.into()).await?;
-            // let peer = peers[rand::thread_rng().gen_range(0..peers.len())];
+            let layout = Layout::new(&data.info, &out)?;
+            let existed = layout.files().iter().any(|f| f.path.exists());
+            let on_disk = layout.files_on_disk();
+            let piece_count = layout.piece_count();
+            let mut storage = Storage::open(layout).await?;
 
-            let mut handler = Client::connect(peers[0], data, info_hash).await?;
+            let resume = ResumeFile::for_output(&out, info_hash);
+            // a resume file says nothing once the data it describes is gone
+            let resumed = if existed {
+                resume.load(piece_count).await?
+            } else {
+                None
+            };
+            let have = match resumed {
+                Some(have) => storage.recheck(&data.info, &have, &on_disk).await?,
+                None if existed => {
+                    eprintln!("No resume file, rehashing existing data");
+                    storage.verify_all(&data.info).await?
+                }
+                None => Bitfield::new(piece_count),
+            };
+            eprintln!("Already have {}/{} pieces", have.count(), piece_count);
+            if have.is_complete() {
+                resume.save(&have).await?;
+                if !seed {
+                    return Ok(());
+                }
+            }
 
-            let mut set = JoinSet::new();
-            let mut pieces = Vec::with_capacity(piece_length.div_ceil(2 << 13) as usize);
-            for begin in (0..piece_length).step_by(2 << 13) {
-                let length = std::cmp::min(piece_length - begin, 2 << 13);
-                let piece = Piece {
-                    index,
-                    begin,
-                    length,
-                };
-                eprintln!("Requesting piece {:?}", piece);
-                let (tx, rx) = oneshot::channel();
-                pieces.push((piece, tx));
-                set.spawn(rx);
+            let store = Arc::new(PieceStore::new(storage, have));
+            let trackers = TrackerList::new(&data);
+            let mut session = Session::new(trackers, info_hash, port, Arc::clone(&store));
+            // magnet links don't always come with a tracker
+            let announcing = !session.is_empty();
+            if announcing {
+                match session.start().await {
+                    Ok(found) => peers.extend(found),
+                    Err(e) if dht.is_some() => eprintln!("tracker failed: {:#}", e),
+                    Err(e) => return Err(e),
+                }
             }
+            if let Some(dht) = &dht {
+                let found = dht.announce(info_hash, port).await;
+                eprintln!("Found {} peers in the DHT", found.len());
+                peers.extend(found);
+            }
+            let mut swarm = Swarm::new(data, info_hash, Arc::clone(&store));
 
-            let mut file = File::create(out).await?;
-            if !handler.request_pieces(pieces).await? {
-                while let Some(res) = set.join_next().await {
-                    if let Some(piece) = res?? {
-                        file.seek(SeekFrom::Start(piece.begin.into()))
-                            .await
-                            .context("seeking in file")?;
-                        file.write(&piece.block).await.context("writing in file")?;
-                    }
+            let listener = Listener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await;
+            let listener = match listener {
+                Ok(listener) => {
+                    swarm.set_port(port);
+                    listener.register(&swarm);
+                    let accept = listener.clone();
+                    tokio::spawn(async move { accept.run().await });
+                    Some(listener)
                 }
+                Err(e) => {
+                    eprintln!("not accepting incoming peers: {:#}", e);
+                    None
+                }
+            };
+
+            let res = tokio::select! {
+                res = swarm.download(&peers, Some(&resume), seed) => res,
+                _ = session.run(|found| found.into_iter().for_each(|addr| swarm.add_peer(addr))),
+                    if announcing => Ok(()),
+                _ = tokio::signal::ctrl_c() => {
+                    eprintln!("interrupted, saving progress");
+                    store.flush().await?;
+                    resume.save(&store.have()).await?;
+                    Ok(())
+                }
+            };
+            if let Some(listener) = listener {
+                listener.unregister(info_hash);
             }
+            store.flush().await?;
+            if announcing {
+                if let Err(e) = session.stop().await {
+                    eprintln!("telling trackers we stopped: {:#}", e);
+                }
+            }
+            res?;
+        }
+        SubCmd::DhtNode { port, dht_nodes } => {
+            let dht = Dht::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await?;
+            println!("Node ID: {}", hex::encode(dht.id()));
+            let run = Arc::clone(&dht);
+            let run = tokio::spawn(async move { run.run().await });
+            if !dht_nodes.is_empty() {
+                dht.bootstrap(&dht_nodes).await?;
+            }
+            run.await??;
         }
-        SubCmd::DownloadFile { out, torrent_file } => {
+        SubCmd::Create {
+            out,
+            path,
+            trackers,
+            piece_length,
+            comment,
+            private,
+            web_seeds,
+            no_date,
+        } => {
+            let options = CreateOptions {
+                piece_length,
+                trackers: trackers
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_string).collect())
+                    .collect(),
+                comment,
+                created_by: Some(extension::CLIENT_VERSION.to_string()),
+                creation_date: if no_date {
+                    None
+                } else {
+                    Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+                },
+                private,
+                web_seeds,
+            };
+            let (info_hash, torrent) = create(&path, &options).await?;
+            tokio::fs::write(&out, torrent)
+                .await
+                .with_context(|| format!("writing {}", out.display()))?;
+            println!("Info Hash: {}", hex::encode(info_hash));
+        }
+        SubCmd::Verify {
+            torrent_file,
+            path,
+            json,
+        } => {
             let (info_hash, data) = Torrent::read_file(torrent_file).await?;
+            let report = verify(&data.info, &path).await?;
 
-            let data_piece_length = data.info.piece_length;
-            let peers = get_peers(&data, info_hash.into()).await?;
-            let mut handler = Client::connect(peers[0], data.clone(), info_hash).await?;
-
-            let mut set = JoinSet::new();
-            let mut pieces = Vec::new();
-            for (index, _) in (0..data.info.length)
-                .step_by(data.info.piece_length as usize)
-                .enumerate()
-            {
-                let index = index as u32;
-
-                let piece_length = if data.info.pieces().count() as u32 - 1 == index {
-                    data.info.length % data_piece_length
+            let total = report.pieces.len();
+            let complete = report.count(PieceStatus::Complete);
+            if json {
+                let summary = serde_json::json!({
+                    "info_hash": hex::encode(info_hash),
+                    "pieces": total,
+                    "complete": complete,
+                    "missing": report.indices(PieceStatus::Missing).collect::<Vec<_>>(),
+                    "corrupt": report.indices(PieceStatus::Corrupt).collect::<Vec<_>>(),
+                    "percent": report.percent_complete(),
+                });
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                println!(
+                    "Complete: {}/{} pieces ({:.1}%)",
+                    complete,
+                    total,
+                    report.percent_complete()
+                );
+                for (label, status) in [
+                    ("Missing", PieceStatus::Missing),
+                    ("Corrupt", PieceStatus::Corrupt),
+                ] {
+                    let count = report.count(status);
+                    if count > 0 {
+                        println!("{}: {} ({})", label, count, ranges(report.indices(status)));
+                    }
+                }
+            }
+            anyhow::ensure!(
+                report.is_complete(),
+                "{} of {} pieces failed verification",
+                total - complete,
+                total
+            );
+        }
+        SubCmd::Scrape {
+            tracker: default,
+            torrents,
+        } => {
+            // torrents sharing a tracker are scraped together
+            let mut by_tracker: Vec<(String, Vec<[u8; 20]>)> = Vec::new();
+            for torrent in torrents {
+                let mut info_hash = [0; 20];
+                let url = if hex::decode_to_slice(&torrent, &mut info_hash).is_ok() {
+                    default
+                        .clone()
+                        .with_context(|| format!("no tracker to scrape {} with", torrent))?
                 } else {
-                    data.info.piece_length
+                    let data;
+                    (info_hash, data) = Torrent::read_file(&torrent)
+                        .await
+                        .with_context(|| format!("reading {}", torrent))?;
+                    let own = data
+                        .announce_list
+                        .iter()
+                        .flatten()
+                        .next()
+                        .unwrap_or(&data.announce);
+                    match &default {
+                        Some(url) => url.clone(),
+                        None if own.is_empty() => anyhow::bail!("{} has no tracker", torrent),
+                        None => own.clone(),
+                    }
                 };
-                // let peer = peers[rand::thread_rng().gen_range(0..peers.len())];
-
-                for begin in (0..piece_length).step_by(2 << 13) {
-                    let length = std::cmp::min(piece_length - begin, 2 << 13);
-                    let piece = Piece {
-                        index,
-                        begin,
-                        length,
-                    };
-                    eprintln!("Requesting piece {:?}", piece);
-                    let (tx, rx) = oneshot::channel();
-                    pieces.push((piece, tx));
-                    set.spawn(rx);
+                match by_tracker.iter_mut().find(|(u, _)| *u == url) {
+                    Some((_, hashes)) => hashes.push(info_hash),
+                    None => by_tracker.push((url, vec![info_hash])),
                 }
             }
 
-            let mut file = File::create(&out).await?;
-            if !handler.request_pieces(pieces).await? {
-                while let Some(res) = set.join_next().await {
-                    if let Some(piece) = res?? {
-                        file.seek(SeekFrom::Start(
-                            (piece.begin + piece.index * data_piece_length) as u64,
-                        ))
-                        .await
-                        .context("seeking in file")?;
-                        file.write(&piece.block).await.context("writing in file")?;
-                    }
+            for (url, info_hashes) in by_tracker {
+                let stats = tracker::scrape(&url, &info_hashes)
+                    .await
+                    .with_context(|| format!("scraping {}", url))?;
+                for (info_hash, stats) in info_hashes.iter().zip(stats) {
+                    println!(
+                        "{}: {} seeders, {} leechers, {} completed",
+                        hex::encode(info_hash),
+                        stats.seeders,
+                        stats.leechers,
+                        stats.completed
+                    );
                 }
             }
         }
+        SubCmd::Tracker { port, interval } => {
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+            let server = Server::bind(addr, Duration::from_secs(interval)).await?;
+            println!("Listening on {}", server.local_addr()?);
+            server.run().await;
+        }
+        SubCmd::MagnetParse { link } => {
+            for tracker in &link.trackers {
+                println!("Tracker URL: {}", tracker);
+            }
+            println!("Info Hash: {}", hex::encode(link.info_hash));
+        }
+        SubCmd::MagnetInfo { link } => {
+            let (info_hash, data) = link.resolve(6881).await?;
+
+            println!("Tracker URL: {}", data.announce);
+            println!("Length: {}", data.info.length());
+            println!("Info Hash: {}", hex::encode(info_hash));
+            println!("Piece Length: {}", data.info.piece_length);
+            println!("Piece Hashes:");
+            for piece in data.info.pieces() {
+                println!("{}", hex::encode(piece));
+            }
+        }
     }
     Ok(())
 }
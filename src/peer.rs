@@ -1,17 +1,25 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 
-use anyhow::{bail, Context};
-use rand::RngCore;
+use anyhow::{bail, ensure, Context};
+use bytes::{Buf, BytesMut};
+use rand::{distributions::Alphanumeric, Rng};
 use tokio::{
     io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::{mpsc, oneshot},
+    sync::broadcast,
 };
 
-use crate::Torrent;
+use crate::{
+    bitfield::Bitfield,
+    extension::{self, ExtendedHandshake, Registry},
+    storage::PieceStore,
+    Torrent,
+};
 
 pub trait AsyncReadExt {
     fn read_bytes<const N: usize>(
@@ -32,11 +40,14 @@ where
 
 #[derive(Debug, Clone)]
 pub enum Message {
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
     NotInterested,
-    Have {},
+    Have {
+        index: u32,
+    },
     Bitfield(Vec<u8>),
     Request {
         index: u32,
@@ -48,8 +59,32 @@ pub enum Message {
         begin: u32,
         block: Vec<u8>,
     },
-    Cancel {},
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
     Port {},
+    /// A message of the extension protocol, where `id` 0 is the extension handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message with a tag we don't know, which is ignored
+    Unknown {
+        tag: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// Largest message we are willing to buffer, well above a block or a bitfield for any sane torrent
+const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+fn be_u32(payload: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .context("message payload too short")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
 }
 
 impl Message {
@@ -58,28 +93,81 @@ impl Message {
         R: AsyncRead + Unpin,
     {
         let len = r.read_u32().await? as usize;
+        if len == 0 {
+            return Ok(Self::KeepAlive);
+        }
+        ensure!(
+            len <= MAX_MESSAGE_LEN,
+            "message of {} bytes is too long",
+            len
+        );
         let tag = r.read_u8().await?;
         let mut payload = vec![0; len - 1];
         r.read_exact(&mut payload).await?;
+        Self::from_payload(tag, payload)
+    }
+
+    /// Take one complete message off the front of `buf`, if it holds one.
+    pub fn parse(buf: &mut BytesMut) -> anyhow::Result<Option<Self>> {
+        let Some(len) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        ensure!(
+            len <= MAX_MESSAGE_LEN,
+            "message of {} bytes is too long",
+            len
+        );
+        if buf.len() < 4 + len {
+            buf.reserve(4 + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(4);
+        if len == 0 {
+            return Ok(Some(Self::KeepAlive));
+        }
+        let mut payload = buf.split_to(len);
+        let tag = payload.get_u8();
+        Self::from_payload(tag, payload.to_vec()).map(Some)
+    }
+
+    fn from_payload(tag: u8, payload: Vec<u8>) -> anyhow::Result<Self> {
         let msg = match tag {
             0 => Self::Choke,
             1 => Self::Unchoke,
             2 => Self::Interested,
             3 => Self::NotInterested,
-            4 => Self::Have {},
+            4 => Self::Have {
+                index: be_u32(&payload, 0)?,
+            },
             5 => Self::Bitfield(payload),
             6 => Self::Request {
-                index: u32::from_be_bytes(payload[0..4].try_into()?),
-                begin: u32::from_be_bytes(payload[4..8].try_into()?),
-                length: u32::from_be_bytes(payload[8..12].try_into()?),
+                index: be_u32(&payload, 0)?,
+                begin: be_u32(&payload, 4)?,
+                length: be_u32(&payload, 8)?,
             },
             7 => Self::Piece {
-                index: u32::from_be_bytes(payload[0..4].try_into()?),
-                begin: u32::from_be_bytes(payload[4..8].try_into()?),
-                block: payload[8..].to_vec(),
+                index: be_u32(&payload, 0)?,
+                begin: be_u32(&payload, 4)?,
+                block: payload
+                    .get(8..)
+                    .context("message payload too short")?
+                    .to_vec(),
+            },
+            8 => Self::Cancel {
+                index: be_u32(&payload, 0)?,
+                begin: be_u32(&payload, 4)?,
+                length: be_u32(&payload, 8)?,
             },
             9 => Self::Port {},
-            t => panic!("Unexpected tag {}", t),
+            20 => {
+                let (&id, payload) = payload.split_first().context("message payload too short")?;
+                Self::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+            tag => Self::Unknown { tag, payload },
         };
         Ok(msg)
     }
@@ -90,13 +178,20 @@ impl Message {
     {
         let mut buf = Vec::new();
         let tag = match self {
+            Message::KeepAlive => {
+                w.write_u32(0).await?;
+                return Ok(());
+            }
             Message::Choke => 0,
             Message::Unchoke => 1,
             Message::Interested => 2,
             Message::NotInterested => 3,
-            Message::Have {} => 4,
+            &Message::Have { index } => {
+                buf.write_u32(index).await?;
+                4
+            }
             Message::Bitfield(v) => {
-                buf.write_all(&v).await?;
+                buf.write_all(v).await?;
                 5
             }
             &Message::Request {
@@ -116,11 +211,29 @@ impl Message {
             } => {
                 buf.write_u32(index).await?;
                 buf.write_u32(begin).await?;
-                buf.write(&block).await?;
+                buf.write_all(block).await?;
                 7
             }
-            Message::Cancel {} => 8,
+            &Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                buf.write_u32(index).await?;
+                buf.write_u32(begin).await?;
+                buf.write_u32(length).await?;
+                8
+            }
             Message::Port {} => 9,
+            Message::Extended { id, payload } => {
+                buf.write_u8(*id).await?;
+                buf.write_all(payload).await?;
+                20
+            }
+            Message::Unknown { tag, payload } => {
+                buf.write_all(payload).await?;
+                *tag
+            }
         };
 
         w.write_u32(buf.len() as u32 + 1).await?;
@@ -138,216 +251,613 @@ pub struct Piece {
     pub length: u32,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub struct DataPiece {
+/// Size of the blocks that pieces are requested in
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// A peer answered a request with a block of the wrong length.
+///
+/// Returned inside the `anyhow::Error` of a failed download so callers can tell a misbehaving
+/// peer apart from one which merely went away.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("block at {begin} of piece {index} is {actual} bytes, expected {expected}")]
+pub struct BadBlock {
     pub index: u32,
     pub begin: u32,
-    pub block: Vec<u8>,
+    pub expected: u32,
+    pub actual: usize,
+}
+
+/// Number of block requests kept in flight to a single peer
+const MAX_PIPELINE: usize = 5;
+
+/// Wait for the next piece to become available, or forever if there is nothing to wait on.
+async fn recv_have(haves: &mut Option<broadcast::Receiver<u32>>) -> Option<u32> {
+    let Some(haves) = haves else {
+        return std::future::pending().await;
+    };
+    loop {
+        match haves.recv().await {
+            Ok(index) => return Some(index),
+            // we can't resend a bitfield, so the peer just misses out on these
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
 }
 
+/// Largest block we will serve, as suggested by the spec
+const MAX_REQUEST_LEN: u32 = 1 << 17;
+
+/// How often an otherwise idle connection checks whether extensions have anything to send
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct Client {
     stream: TcpStream,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    /// We opened the connection, rather than the peer connecting to us
+    outbound: bool,
+    /// The peer set the extension protocol bit in its handshake
+    supports_extensions: bool,
+    extensions: Registry,
+    /// Bytes read from `stream` which don't yet make up a whole message
+    buf: BytesMut,
     data: Torrent,
     info_hash: [u8; 20],
-    bitfield: Option<Vec<u8>>,
+    bitfield: Bitfield,
+    /// The peer is choking us
     choked: bool,
+    /// We have told the peer we are interested
+    interested: bool,
+    /// We are choking the peer
+    choking: bool,
+    /// Our pieces, if we are willing to upload
+    store: Option<Arc<PieceStore>>,
+    /// Pieces which have become available in `store` since we last told the peer
+    haves: Option<broadcast::Receiver<u32>>,
 }
 
 impl Client {
+    /// Connect and handshake with a peer, telling it which pieces of `store` we have and which of
+    /// `extensions` we support.
+    ///
+    /// Without a store we only ever download.
     pub async fn connect(
         s: SocketAddr,
         data: Torrent,
         info_hash: [u8; 20],
+        store: Option<Arc<PieceStore>>,
+        extensions: Registry,
     ) -> anyhow::Result<Self> {
-        let mut ret = Self {
-            stream: TcpStream::connect(s).await?,
-            data,
-            info_hash,
-            bitfield: None,
-            choked: false, // can be default since we assert that we get `Unchoke`.
-        };
+        let stream = TcpStream::connect(s).await?;
+        let mut ret = Self::new(stream, data, info_hash, store, extensions)?;
+        ret.outbound = true;
         ret.handshake().await?;
+        ret.send_bitfield().await?;
+        Ok(ret)
+    }
 
-        let Message::Bitfield(bitfield) = Message::read_from(&mut ret.stream)
-            .await
-            .context("reading bitfield message")?
-        else {
-            bail!("First message received should be Bitfield.");
-        };
-        ret.bitfield = Some(bitfield);
-
-        dbg!(Message::Interested)
+    /// Take over a connection from a peer which has already sent its handshake, responding with
+    /// ours.
+    pub async fn accept(
+        stream: TcpStream,
+        theirs: Handshake,
+        data: Torrent,
+        store: Option<Arc<PieceStore>>,
+        extensions: Registry,
+    ) -> anyhow::Result<Self> {
+        let mut ret = Self::new(stream, data, theirs.info_hash, store, extensions)?;
+        Handshake::new(ret.info_hash)
             .write_to(&mut ret.stream)
             .await
-            .context("sending interest message")?;
+            .context("sending handshake")?;
+        ret.peer_id = theirs.peer_id;
+        ret.supports_extensions = theirs.supports_extensions();
+        ret.send_bitfield().await?;
+        Ok(ret)
+    }
 
-        let Message::Unchoke = Message::read_from(&mut ret.stream)
+    fn new(
+        stream: TcpStream,
+        data: Torrent,
+        info_hash: [u8; 20],
+        store: Option<Arc<PieceStore>>,
+        extensions: Registry,
+    ) -> anyhow::Result<Self> {
+        let piece_count = data.info.pieces().count() as u32;
+        Ok(Self {
+            addr: stream.peer_addr()?,
+            stream,
+            buf: BytesMut::new(),
+            data,
+            info_hash,
+            peer_id: [0; 20],
+            outbound: false,
+            supports_extensions: false,
+            extensions,
+            // peers with no pieces may skip the bitfield entirely
+            bitfield: Bitfield::new(piece_count),
+            choked: true,
+            interested: false,
+            choking: true,
+            haves: store.as_ref().map(|store| store.subscribe()),
+            store,
+        })
+    }
+
+    /// Send everything that has to follow the handshake: our bitfield and extension handshake.
+    async fn send_bitfield(&mut self) -> anyhow::Result<()> {
+        if let Some(store) = &self.store {
+            let have = store.have();
+            if have.count() > 0 {
+                Message::Bitfield(have.as_bytes().to_vec())
+                    .write_to(&mut self.stream)
+                    .await
+                    .context("sending bitfield")?;
+            }
+        }
+        if self.supports_extensions {
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: self.extensions.handshake().to_bytes()?,
+            }
+            .write_to(&mut self.stream)
             .await
-            .context("reading unchoke message")?
-        else {
-            bail!("expected unchoke message");
-        };
+            .context("sending extension handshake")?;
+        }
+        Ok(())
+    }
 
-        // dbg!(Message::Request {
-        //     index: 0,
-        //     begin: 0,
-        //     length: 32,
-        // })
-        // .write_to(&mut ret.stream)
-        // .await?;
-        // let msg = Message::read_from(&mut ret.stream).await?;
-        // dbg!(msg);
-        // let msg = Message::read_from(&mut ret.stream).await?;
-        // dbg!(msg);
-        // let msg = Message::read_from(&mut ret.stream).await?;
-        // dbg!(msg);
-        // let msg = Message::read_from(&mut ret.stream).await?;
-        // dbg!(msg);
-        Ok(ret)
+    /// The peer's extension handshake, once it has sent one
+    pub fn extensions(&self) -> Option<&ExtendedHandshake> {
+        self.extensions.theirs()
     }
 
-    async fn add_pieces<W>(w: &mut W, pieces: &[Piece]) -> anyhow::Result<()>
-    where
-        W: AsyncWrite + Unpin,
-    {
-        for &piece in pieces {
-            dbg!(Message::Request {
-                index: piece.index as u32,
-                begin: piece.begin as u32,
-                length: piece.length as u32,
-            })
-            .write_to(w)
-            .await?;
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address the peer accepts connections on, if known.
+    ///
+    /// For peers which connected to us this is only known once they send their port in the
+    /// extension handshake.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        if self.outbound {
+            return Some(self.addr);
+        }
+        let port = self.extensions()?.p?;
+        Some(SocketAddr::new(self.addr.ip(), port))
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.data
+    }
+
+    /// Whether the peer has advertised piece `index`, either in its bitfield or with `Have`.
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.has(index)
+    }
+
+    pub fn is_choked(&self) -> bool {
+        self.choked
+    }
+
+    /// Tell the peer we want to download from it.
+    pub async fn interested(&mut self) -> anyhow::Result<()> {
+        if !self.interested {
+            Message::Interested
+                .write_to(&mut self.stream)
+                .await
+                .context("sending interest message")?;
+            self.interested = true;
         }
         Ok(())
     }
 
-    // Ok(None) if choked while requesting pieces
-    // Ok(Some(vec![Some(a), None(b)])) if piece a succeeded but choked before piece b
-    // Ok(true) if choked
-    // Ok(false) if not choked
-    pub async fn request_pieces(
-        &mut self,
-        pieces: Vec<(Piece, oneshot::Sender<Option<DataPiece>>)>,
-    ) -> anyhow::Result<bool> {
-        let (mut read, mut write) = self.stream.split();
+    /// Read the next message from the peer.
+    ///
+    /// This is cancel safe, partially received messages are kept until the next call.
+    async fn read_message(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let Some(message) = Message::parse(&mut self.buf)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                bail!("connection closed by peer");
+            }
+        }
+    }
 
-        let just_pieces: Vec<_> = pieces.iter().map(|(k, _)| k).copied().collect();
+    /// Read messages until the peer unchokes us, keeping track of the pieces it announces.
+    pub async fn wait_unchoke(&mut self) -> anyhow::Result<()> {
+        while self.choked {
+            self.send_updates().await?;
+            let message = self.read_message().await.context("waiting for unchoke")?;
+            self.handle_message(message).await?;
+        }
+        Ok(())
+    }
 
-        let mut pieces: HashMap<_, _> = pieces
-            .into_iter()
-            .map(|(k, v)| ((k.index, k.begin), v))
-            .collect();
+    /// Handle the next message from the peer, unless `interrupt` completes first.
+    ///
+    /// This keeps track of the pieces the peer announces and serves its requests while there is
+    /// nothing to download from it. Updates are sent first, and at least every
+    /// [`UPDATE_INTERVAL`] if the peer is quiet.
+    pub async fn handle_next(
+        &mut self,
+        interrupt: impl std::future::Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        self.send_updates().await?;
+        // only reading is cancel safe, so the message is handled once nothing else can win
+        let message = tokio::select! {
+            message = self.read_message() => message?,
+            () = interrupt => return Ok(()),
+            () = tokio::time::sleep(UPDATE_INTERVAL) => return Ok(()),
+        };
+        self.handle_message(message).await
+    }
 
-        let add = Self::add_pieces(&mut write, &just_pieces[..]);
-        tokio::pin!(add);
-
-        let choked = loop {
-            tokio::select! {
-                Ok(message) = Message::read_from(&mut read) => {
-                    match message {
-                        Message::Choke => {
-                    eprintln!("choked 1");
-                            break true;
-                        },
-                        Message::Piece { index, begin, block } => {
-                            if let Some(piece) = pieces.remove(&(index, begin)) {
-                                let data = DataPiece { index, begin, block };
-                                if let Err(e) = piece.send(Some(data)) {
-                                    eprintln!("ignoring dropped piece: {:?}", e);
-                                }
-                            }
-                        }
-                        _ => bail!("Unexpected message while requesting pieces: {:?}", message),
-                    }
-                },
-                res = &mut add => {
-                    res?;
-                    break false;
+    /// Upload to the peer until it disconnects.
+    pub async fn serve(&mut self) -> anyhow::Result<()> {
+        if self.interested {
+            Message::NotInterested
+                .write_to(&mut self.stream)
+                .await
+                .context("sending not interested message")?;
+            self.interested = false;
+        }
+        let mut haves = self.haves.take();
+        let mut updates = tokio::time::interval(UPDATE_INTERVAL);
+        let res = loop {
+            let res = tokio::select! {
+                message = self.read_message() => match message {
+                    Ok(message) => self.handle_message(message).await,
+                    Err(e) => Err(e),
                 },
+                Some(index) = recv_have(&mut haves) => {
+                    Message::Have { index }.write_to(&mut self.stream).await
+                }
+                _ = updates.tick() => self.send_updates().await,
             };
+            if let Err(e) = res {
+                break Err(e);
+            }
         };
-        if choked {
-            self.choked = choked;
-            return Ok(true);
+        self.haves = haves;
+        res
+    }
+
+    /// Tell the peer about every piece that has become available since we last did, and send
+    /// anything extensions have queued up.
+    async fn send_updates(&mut self) -> anyhow::Result<()> {
+        if let Some(haves) = &mut self.haves {
+            loop {
+                let index = match haves.try_recv() {
+                    Ok(index) => index,
+                    Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                };
+                Message::Have { index }
+                    .write_to(&mut self.stream)
+                    .await
+                    .context("sending have")?;
+            }
         }
-        let choked = loop {
-            let message = Message::read_from(&mut read)
+        for message in self.extensions.poll() {
+            message
+                .write_to(&mut self.stream)
                 .await
-                .context("reading message")?;
-            match message {
-                Message::Choke => {
-                    self.choked = true;
-                    eprintln!("choked 2");
+                .context("sending extended message")?;
+        }
+        Ok(())
+    }
+
+    /// Update our view of the peer from a message that isn't a response to one of our requests,
+    /// serving any blocks it asks for.
+    async fn handle_message(&mut self, message: Message) -> anyhow::Result<()> {
+        match message {
+            Message::Choke => self.choked = true,
+            Message::Unchoke => self.choked = false,
+            Message::Bitfield(bitfield) => {
+                self.bitfield = Bitfield::from_bytes(bitfield, self.bitfield.len())?;
+            }
+            Message::Have { index } => self.bitfield.set(index),
+            Message::Interested if self.store.is_some() => {
+                // no choking algorithm yet, everyone interested gets to download
+                if self.choking {
+                    Message::Unchoke
+                        .write_to(&mut self.stream)
+                        .await
+                        .context("sending unchoke")?;
+                    self.choking = false;
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => self.serve_request(index, begin, length).await?,
+            // blocks for a piece we have since given up on
+            Message::Piece { .. } => {}
+            // requests are served as soon as they arrive, so there is never one to cancel
+            Message::Cancel { .. } => {}
+            Message::KeepAlive
+            | Message::Interested
+            | Message::NotInterested
+            | Message::Port {}
+            | Message::Unknown { .. } => {}
+            Message::Extended { id, payload } => {
+                for reply in self.extensions.handle(id, &payload)? {
+                    reply
+                        .write_to(&mut self.stream)
+                        .await
+                        .context("sending extended message")?;
                 }
+            }
+        }
+        Ok(())
+    }
+
+    async fn serve_request(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if self.choking {
+            // the peer should know better, but it isn't worth dropping the connection over
+            return Ok(());
+        }
+        ensure!(
+            length <= MAX_REQUEST_LEN,
+            "peer requested a block of {} bytes",
+            length
+        );
+        let block = store.read_block(index, begin, length).await?;
+        Message::Piece {
+            index,
+            begin,
+            block,
+        }
+        .write_to(&mut self.stream)
+        .await
+        .context("sending piece")
+    }
+
+    /// Download the whole of piece `index`, pipelining block requests.
+    ///
+    /// Returns `Ok(None)` if the peer choked us before the piece was complete, in which case any
+    /// outstanding requests have been discarded by the peer.
+    pub async fn download_piece(
+        &mut self,
+        index: u32,
+        length: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let blocks: Vec<_> = (0..length)
+            .step_by(BLOCK_SIZE as usize)
+            .map(|begin| Piece {
+                index,
+                begin,
+                length: std::cmp::min(length - begin, BLOCK_SIZE),
+            })
+            .collect();
+
+        let mut piece = vec![0; length as usize];
+        let mut next = 0;
+        let mut received = 0;
+        // requested blocks by offset, with their length
+        let mut outstanding = HashMap::new();
+        while received < blocks.len() {
+            // peers may tell us they queue fewer requests than we would like to pipeline
+            let pipeline = match self.extensions().and_then(|e| e.reqq) {
+                Some(reqq) => (reqq as usize).clamp(1, MAX_PIPELINE),
+                None => MAX_PIPELINE,
+            };
+            while !self.choked && outstanding.len() < pipeline && next < blocks.len() {
+                let block = blocks[next];
+                Message::Request {
+                    index: block.index,
+                    begin: block.begin,
+                    length: block.length,
+                }
+                .write_to(&mut self.stream)
+                .await
+                .context("sending request")?;
+                outstanding.insert(block.begin, block.length);
+                next += 1;
+            }
+
+            self.send_updates().await?;
+            let message = self.read_message().await.context("reading message")?;
+            match message {
                 Message::Piece {
-                    index,
+                    index: i,
                     begin,
                     block,
-                } => {
-                    eprintln!("recieved piece {}", index);
-                    if let Some(piece) = pieces.remove(&(index, begin)) {
-                        let data = DataPiece {
+                } if i == index && outstanding.contains_key(&begin) => {
+                    let expected = outstanding[&begin];
+                    if block.len() != expected as usize {
+                        return Err(BadBlock {
                             index,
                             begin,
-                            block,
-                        };
-                        match piece.send(Some(data)) {
-                            Ok(()) => {
-                                if pieces.is_empty() {
-                                    break false;
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("ignoring dropped piece: {:?}", e);
-                            }
+                            expected,
+                            actual: block.len(),
                         }
+                        .into());
                     }
+                    let start = begin as usize;
+                    piece[start..start + block.len()].copy_from_slice(&block);
+                    outstanding.remove(&begin);
+                    received += 1;
                 }
-                _ => bail!("Unexpected message while requesting pieces: {:?}", message),
+                Message::Choke => {
+                    self.choked = true;
+                    return Ok(None);
+                }
+                message => self.handle_message(message).await?,
             }
-        };
-        if choked {
-            self.choked = choked;
-            Ok(true)
-        } else {
-            Ok(false)
         }
+
+        Ok(Some(piece))
     }
 
     async fn handshake(&mut self) -> anyhow::Result<[u8; 20]> {
-        let prot_str = b"BitTorrent protocol";
-
-        self.stream.write_all(&[prot_str.len() as u8]).await?;
-        self.stream.write_all(prot_str).await?;
-        self.stream.write_all(&[0; 8]).await?;
-        self.stream.write_all(&self.info_hash).await?;
-        let mut my_id = [0; 20];
-        rand::thread_rng().fill_bytes(&mut my_id);
-        self.stream.write_all(&my_id).await?;
-        eprintln!("my_id = {:02x?}", my_id);
-
-        let protocol_len = self.stream.read_u8().await? as usize;
-        assert_eq!(
-            protocol_len,
-            prot_str.len(),
+        Handshake::new(self.info_hash)
+            .write_to(&mut self.stream)
+            .await
+            .context("sending handshake")?;
+
+        let theirs = Handshake::read_from(&mut self.stream).await?;
+        ensure!(theirs.info_hash == self.info_hash, "Info hash not equal");
+        ensure!(theirs.peer_id != peer_id(), "connected to ourselves");
+        eprintln!("Peer ID: {}", hex::encode(theirs.peer_id));
+        self.peer_id = theirs.peer_id;
+        self.supports_extensions = theirs.supports_extensions();
+
+        Ok(theirs.peer_id)
+    }
+}
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// The handshake which starts every peer connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Our handshake for the torrent with `info_hash`.
+    pub fn new(info_hash: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[5] |= extension::RESERVED_BIT;
+        Self {
+            reserved,
+            info_hash,
+            peer_id: peer_id(),
+        }
+    }
+
+    /// Whether the sender supports the extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & extension::RESERVED_BIT != 0
+    }
+
+    pub async fn read_from<R>(r: &mut R) -> anyhow::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let protocol_len = r.read_u8().await? as usize;
+        ensure!(
+            protocol_len == PROTOCOL.len(),
             "protocol name lengths not equal"
         );
         let mut buf = vec![0u8; protocol_len];
-        self.stream.read_exact(&mut buf).await?;
-        assert_eq!(buf, *prot_str, "protocol names not equal");
+        r.read_exact(&mut buf).await?;
+        ensure!(buf == PROTOCOL, "protocol names not equal");
 
-        let _reserved = self.stream.read_bytes::<8>().await?;
         // Don't want to check this since they can be set for extensions.
-        // assert_eq!([0; 8], reserved, "Reserved bytes should be set to 0.");
+        let reserved = r.read_bytes::<8>().await?;
+        let info_hash = r.read_bytes::<20>().await?;
+        let peer_id = r.read_bytes::<20>().await?;
 
-        let buf = self.stream.read_bytes::<20>().await?;
-        assert_eq!(&buf[..], &self.info_hash[..], "Info has not equal");
+        Ok(Self {
+            reserved,
+            info_hash,
+            peer_id,
+        })
+    }
 
-        let peer_id = self.stream.read_bytes::<20>().await?;
-        eprintln!("Peer ID: {}", hex::encode(peer_id));
+    pub async fn write_to<W>(&self, w: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::with_capacity(68);
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+        w.write_all(&buf).await?;
+        Ok(())
+    }
+}
 
-        Ok(peer_id)
+/// Our peer id, randomly generated once per process and used for every torrent and tracker.
+pub fn peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *PEER_ID.get_or_init(|| {
+        // printable so it can go in tracker URLs as is
+        let mut id = *b"-BC0001-000000000000";
+        for (b, c) in id[8..]
+            .iter_mut()
+            .zip(rand::thread_rng().sample_iter(Alphanumeric))
+        {
+            *b = c;
+        }
+        id
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::decode::Value;
+
+    #[tokio::test]
+    async fn blocks_of_the_wrong_length_are_refused() {
+        let mut info = Vec::new();
+        Value::dict()
+            .with("name", "file")
+            .with("length", 100)
+            .with("piece length", 1 << 14)
+            .with("pieces", vec![0; 20])
+            .encode(&mut info)
+            .unwrap();
+        let (info_hash, torrent) = Torrent::from_info(String::new(), &info).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a peer which unchokes us and then sends a block shorter than any we could ask for
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Handshake::read_from(&mut stream).await.unwrap();
+            let theirs = Handshake {
+                reserved: [0; 8],
+                info_hash,
+                peer_id: [b'x'; 20],
+            };
+            theirs.write_to(&mut stream).await.unwrap();
+            Message::Unchoke.write_to(&mut stream).await.unwrap();
+            let block = Message::Piece {
+                index: 0,
+                begin: 0,
+                block: vec![1; 10],
+            };
+            block.write_to(&mut stream).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+
+        let mut client = Client::connect(addr, torrent, info_hash, None, Registry::new())
+            .await
+            .unwrap();
+        let err = client.download_piece(0, 100).await.unwrap_err();
+        assert_eq!(
+            err.downcast::<BadBlock>().unwrap(),
+            BadBlock {
+                index: 0,
+                begin: 0,
+                expected: 100,
+                actual: 10,
+            }
+        );
     }
 }
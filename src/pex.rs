@@ -0,0 +1,273 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tokio::sync::mpsc;
+
+use crate::{
+    decode::{decode, Decoded, Value},
+    extension::{ExtendedHandshake, Extension},
+};
+
+/// Name of the peer exchange extension (BEP 11)
+pub const UT_PEX: &str = "ut_pex";
+
+/// Peers should not be sent more than one exchange message a minute
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers to list as added or dropped in a single message
+const MAX_PEX_PEERS: usize = 50;
+
+/// Most discovered peers waiting to be connected to, beyond which more are dropped
+const MAX_DISCOVERED: usize = 1000;
+
+/// The peer prefers encrypted connections
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP
+pub const FLAG_UTP: u8 = 0x04;
+/// The peer supports holepunching
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+/// The peer accepts incoming connections
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// The peers of one swarm, shared between the `ut_pex` handlers of all its connections.
+#[derive(Debug)]
+pub struct PeerSet {
+    /// Connected peers by the address they accept connections on, with their flags
+    connected: Mutex<HashMap<SocketAddr, u8>>,
+    /// Peers other peers have told us about, and any found since the swarm started
+    discovered: mpsc::Sender<SocketAddr>,
+}
+
+impl PeerSet {
+    /// An empty set, along with the receiving end for the peers learned through exchange.
+    pub fn new() -> (Arc<Self>, mpsc::Receiver<SocketAddr>) {
+        let (discovered, rx) = mpsc::channel(MAX_DISCOVERED);
+        let set = Self {
+            connected: Mutex::default(),
+            discovered,
+        };
+        (Arc::new(set), rx)
+    }
+
+    /// Advertise `addr` to other peers as one we are connected to.
+    pub fn insert(&self, addr: SocketAddr, flags: u8) {
+        self.connected.lock().unwrap().insert(addr, flags);
+    }
+
+    pub fn remove(&self, addr: SocketAddr) {
+        self.connected.lock().unwrap().remove(&addr);
+    }
+
+    /// Pass on a peer learned about some other way, such as from a tracker.
+    ///
+    /// The peer is dropped if too many are already waiting to be connected to.
+    pub fn discover(&self, addr: SocketAddr) {
+        // the receiver only goes away once the swarm is done connecting to peers
+        let _ = self.discovered.try_send(addr);
+    }
+}
+
+/// Exchanges lists of connected peers with a single peer.
+#[derive(Debug)]
+pub struct Pex {
+    peers: Arc<PeerSet>,
+    /// The address of the peer we exchange with, which is never worth telling it about
+    peer: SocketAddr,
+    /// The address the peer accepts connections on, once it has told us its port
+    listen_addr: Option<SocketAddr>,
+    /// The peers we have told this peer about
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl Pex {
+    /// Exchange peers with the peer at `peer`.
+    pub fn new(peers: Arc<PeerSet>, peer: SocketAddr) -> Self {
+        Self {
+            peers,
+            peer,
+            listen_addr: None,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+
+    /// Whether `addr` is one of the peer's own addresses.
+    fn is_peer(&self, addr: SocketAddr) -> bool {
+        addr == self.peer || Some(addr) == self.listen_addr
+    }
+}
+
+impl Extension for Pex {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_handshake(&mut self, theirs: &ExtendedHandshake) {
+        self.listen_addr = theirs.p.map(|port| SocketAddr::new(self.peer.ip(), port));
+    }
+
+    fn handle(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let (_, value) = decode(payload).context("decoding ut_pex message")?;
+        if value.as_dict().is_none() {
+            bail!("ut_pex message is not a dictionary");
+        }
+        // a peer sending more often than allowed doesn't get to flood us with addresses
+        let now = Instant::now();
+        if self
+            .last_received
+            .replace(now)
+            .is_some_and(|last| now.duration_since(last) < PEX_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        let field = |key| {
+            value
+                .get(key)
+                .and_then(Decoded::as_bytes)
+                .unwrap_or_default()
+        };
+        let added = parse_peers(field("added"), 4)
+            .chain(parse_peers(field("added6"), 16))
+            .take(MAX_PEX_PEERS);
+        for addr in added {
+            self.peers.discover(addr);
+        }
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|last| last.elapsed() < PEX_INTERVAL)
+        {
+            return Vec::new();
+        }
+        let connected = self.peers.connected.lock().unwrap().clone();
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|(&addr, _)| !self.sent.contains(&addr) && !self.is_peer(addr))
+            .map(|(&addr, &flags)| (addr, flags))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .iter()
+            .copied()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+        for (addr, _) in &added {
+            self.sent.insert(*addr);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+        vec![encode_message(&added, &dropped)]
+    }
+}
+
+/// Encode a `ut_pex` message, split into IPv4 and IPv6 lists.
+fn encode_message(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Vec<u8> {
+    let mut lists: [Vec<u8>; 6] = Default::default();
+    let [added4, added4_f, dropped4, added6, added6_f, dropped6] = &mut lists;
+    for &(addr, flags) in added {
+        if addr.is_ipv4() {
+            compact(addr, added4);
+            added4_f.push(flags);
+        } else {
+            compact(addr, added6);
+            added6_f.push(flags);
+        }
+    }
+    for &addr in dropped {
+        compact(addr, if addr.is_ipv4() { dropped4 } else { dropped6 });
+    }
+
+    let keys = [
+        "added", "added.f", "dropped", "added6", "added6.f", "dropped6",
+    ];
+    let dict: Value = keys.into_iter().zip(lists).collect();
+    let mut buf = Vec::new();
+    dict.encode(&mut buf).expect("writing to a Vec can't fail");
+    buf
+}
+
+/// Append `addr` in compact form: the address bytes followed by the port in network order.
+fn compact(addr: SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Parse a compact peer list of `ip_len` byte addresses, ignoring any trailing partial entry.
+fn parse_peers(list: &[u8], ip_len: usize) -> impl Iterator<Item = SocketAddr> + '_ {
+    list.chunks_exact(ip_len + 2).map(move |chunk| {
+        let (ip, port) = chunk.split_at(ip_len);
+        let ip = match <[u8; 4]>::try_from(ip) {
+            Ok(ip) => IpAddr::V4(Ipv4Addr::from(ip)),
+            Err(_) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+        };
+        SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_not_told_about_themselves() {
+        let (peers, _discovered) = PeerSet::new();
+        let other: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let inbound: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let listening: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        for addr in [other, inbound, listening] {
+            peers.insert(addr, 0);
+        }
+
+        let mut pex = Pex::new(Arc::clone(&peers), inbound);
+        pex.on_handshake(&ExtendedHandshake {
+            p: Some(6881),
+            ..Default::default()
+        });
+        let messages = pex.poll();
+        assert_eq!(messages.len(), 1);
+        let (_, value) = decode(&messages[0]).unwrap();
+        let added = value.get("added").and_then(Decoded::as_bytes).unwrap();
+        assert_eq!(parse_peers(added, 4).collect::<Vec<_>>(), [other]);
+    }
+
+    #[test]
+    fn peers_sending_too_often_are_ignored() {
+        let (peers, mut discovered) = PeerSet::new();
+        let mut pex = Pex::new(peers, "10.0.0.1:6881".parse().unwrap());
+        let added = |port: u16| {
+            let addr = SocketAddr::from(([10, 0, 0, 9], port));
+            encode_message(&[(addr, 0)], &[])
+        };
+
+        pex.handle(&added(1)).unwrap();
+        pex.handle(&added(2)).unwrap();
+        assert_eq!(
+            discovered.try_recv().unwrap(),
+            SocketAddr::from(([10, 0, 0, 9], 1))
+        );
+        assert!(discovered.try_recv().is_err());
+    }
+}
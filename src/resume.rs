@@ -0,0 +1,90 @@
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    bitfield::Bitfield,
+    decode::{decode, Decoded, Value},
+};
+
+/// A bencoded sidecar next to a download recording which pieces have been verified, so an
+/// interrupted download can pick up where it left off.
+///
+/// The file is a dictionary with the torrent's `info hash` and a `pieces` bitfield.
+#[derive(Debug, Clone)]
+pub struct ResumeFile {
+    path: PathBuf,
+    info_hash: [u8; 20],
+}
+
+impl ResumeFile {
+    /// The resume file for a download written to `out`, stored at `<out>.resume`.
+    pub fn for_output(out: impl AsRef<Path>, info_hash: [u8; 20]) -> Self {
+        let mut path = OsString::from(out.as_ref());
+        path.push(".resume");
+        Self {
+            path: path.into(),
+            info_hash,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the verified pieces, or `None` if there is no resume file for this torrent.
+    pub async fn load(&self, piece_count: u32) -> anyhow::Result<Option<Bitfield>> {
+        let file = match tokio::fs::read(&self.path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", self.path.display()));
+            }
+        };
+        let (_, value) =
+            decode(&file).with_context(|| format!("decoding {}", self.path.display()))?;
+        if value.as_dict().is_none() {
+            bail!("{} is not a dictionary", self.path.display());
+        }
+
+        let info_hash = value.get("info hash").and_then(Decoded::as_bytes);
+        if info_hash != Some(&self.info_hash[..]) {
+            eprintln!(
+                "ignoring {} as it belongs to another torrent",
+                self.path.display()
+            );
+            return Ok(None);
+        }
+        let pieces = value
+            .get("pieces")
+            .and_then(Decoded::as_bytes)
+            .context("resume file is missing `pieces`")?;
+        Ok(Some(Bitfield::from_bytes(pieces.to_vec(), piece_count)?))
+    }
+
+    /// Record `have` as the set of verified pieces.
+    ///
+    /// The file is written alongside and then renamed into place so that it is never left
+    /// half-written.
+    pub async fn save(&self, have: &Bitfield) -> anyhow::Result<()> {
+        let value = Value::dict()
+            .with("info hash", &self.info_hash[..])
+            .with("pieces", have.as_bytes());
+        let mut buf = Vec::new();
+        value.encode(&mut buf)?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, buf)
+            .await
+            .with_context(|| format!("writing {}", self.path.display()))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("writing {}", self.path.display()))?;
+        Ok(())
+    }
+}
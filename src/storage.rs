@@ -0,0 +1,519 @@
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, ensure, Context};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::broadcast,
+};
+
+use crate::{bitfield::Bitfield, FileLayout, TorrentInfo};
+
+/// A single file of the torrent, positioned within the torrent's contiguous byte stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    /// Offset of the first byte of this file within the whole torrent
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The part of a block that lives in one particular file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Index into [`Layout::files`]
+    pub file: usize,
+    /// Offset within that file
+    pub offset: u64,
+    /// Range of the block which belongs to this file
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Maps pieces onto the files that make up a torrent.
+///
+/// Pieces are laid out over the concatenation of every file in the torrent, so a piece may span
+/// the end of one file and the start of the next.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<FileEntry>,
+    piece_length: u32,
+    piece_count: u32,
+    total_length: u64,
+}
+
+impl Layout {
+    /// Build the layout for `info`, placing files relative to `root`.
+    ///
+    /// For a single-file torrent `root` is the path of the file itself, otherwise it is the
+    /// directory under which each file's `path` is created.
+    pub fn new(info: &TorrentInfo, root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref();
+        let mut files = Vec::new();
+        let mut offset = 0;
+        match &info.files {
+            FileLayout::Single { length } => {
+                files.push(FileEntry {
+                    path: root.to_path_buf(),
+                    offset,
+                    length: *length,
+                });
+                offset += length;
+            }
+            FileLayout::Multi { files: entries } => {
+                for entry in entries {
+                    files.push(FileEntry {
+                        path: root.join(sanitize_path(&entry.path)?),
+                        offset,
+                        length: entry.length,
+                    });
+                    offset += entry.length;
+                }
+            }
+        }
+
+        let piece_count = info.pieces().count() as u32;
+        ensure!(info.piece_length > 0, "piece length must be non-zero");
+        ensure!(
+            offset.div_ceil(info.piece_length.into()) == u64::from(piece_count),
+            "torrent length {} does not match {} pieces of {} bytes",
+            offset,
+            piece_count,
+            info.piece_length
+        );
+
+        Ok(Self {
+            files,
+            piece_length: info.piece_length,
+            piece_count,
+            total_length: offset,
+        })
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    /// Whether each file is on disk with at least its full length, as a download leaves it.
+    pub fn files_on_disk(&self) -> Vec<bool> {
+        self.files
+            .iter()
+            .map(|f| f.path.metadata().is_ok_and(|m| m.len() >= f.length))
+            .collect()
+    }
+
+    /// Length of the piece at `index`, accounting for a short final piece.
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        std::cmp::min(self.piece_length as u64, self.total_length - start) as u32
+    }
+
+    /// Split `length` bytes starting at `begin` within piece `index` into per-file spans.
+    pub fn spans(&self, index: u32, begin: u32, length: usize) -> Vec<Span> {
+        let start = index as u64 * self.piece_length as u64 + begin as u64;
+        let end = start + length as u64;
+        let first = self.files.partition_point(|f| f.offset + f.length <= start);
+
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, f)| f.offset < end)
+            // zero-length files never hold any piece data
+            .filter(|(_, f)| f.length > 0)
+            .map(|(i, f)| {
+                let lo = std::cmp::max(start, f.offset);
+                let hi = std::cmp::min(end, f.offset + f.length);
+                Span {
+                    file: first + i,
+                    offset: lo - f.offset,
+                    start: (lo - start) as usize,
+                    end: (hi - start) as usize,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reject path components that would escape the download directory.
+fn sanitize_path(components: &[String]) -> anyhow::Result<PathBuf> {
+    ensure!(!components.is_empty(), "file path must not be empty");
+    let mut path = PathBuf::new();
+    for component in components {
+        let mut parts = Path::new(component).components();
+        match (parts.next(), parts.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => bail!("invalid path component {:?}", component),
+        }
+    }
+    Ok(path)
+}
+
+/// On-disk storage for a torrent's files.
+#[derive(Debug)]
+pub struct Storage {
+    layout: Layout,
+    handles: Vec<File>,
+}
+
+impl Storage {
+    /// Open (creating if needed) every file described by `layout`.
+    ///
+    /// Existing data is kept so that previously downloaded pieces survive.
+    pub async fn open(layout: Layout) -> anyhow::Result<Self> {
+        let mut handles = Vec::with_capacity(layout.files.len());
+        for entry in &layout.files {
+            if let Some(parent) = entry.path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("creating directory {}", parent.display()))?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&entry.path)
+                .await
+                .with_context(|| format!("opening {}", entry.path.display()))?;
+            file.set_len(entry.length)
+                .await
+                .with_context(|| format!("resizing {}", entry.path.display()))?;
+            handles.push(file);
+        }
+        Ok(Self { layout, handles })
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Write a block of piece `index` starting at `begin`, splitting it across files as needed.
+    pub async fn write_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+    ) -> anyhow::Result<()> {
+        for span in self.layout.spans(index, begin, block.len()) {
+            let file = &mut self.handles[span.file];
+            file.seek(SeekFrom::Start(span.offset))
+                .await
+                .context("seeking in file")?;
+            file.write_all(&block[span.start..span.end])
+                .await
+                .context("writing in file")?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes of piece `index` starting at `begin`.
+    pub async fn read_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut block = vec![0; length as usize];
+        for span in self.layout.spans(index, begin, block.len()) {
+            let file = &mut self.handles[span.file];
+            file.seek(SeekFrom::Start(span.offset))
+                .await
+                .context("seeking in file")?;
+            file.read_exact(&mut block[span.start..span.end])
+                .await
+                .context("reading from file")?;
+        }
+        Ok(block)
+    }
+
+    /// Rehash every piece already on disk, returning the set of pieces that verify.
+    pub async fn verify_all(&mut self, info: &TorrentInfo) -> anyhow::Result<Bitfield> {
+        let mut have = Bitfield::new(self.layout.piece_count);
+        for index in 0..self.layout.piece_count {
+            let piece = self
+                .read_block(index, 0, self.layout.piece_len(index))
+                .await?;
+            if info.verify_piece(index, &piece) {
+                have.set(index);
+            }
+        }
+        Ok(have)
+    }
+
+    /// Rehash the pieces of `have` which lie partly in a file that isn't `on_disk`, returning
+    /// `have` without the ones that no longer verify.
+    ///
+    /// Such files are recreated full of zeros by [`Storage::open`], so whatever recorded `have`
+    /// can't be trusted about them.
+    pub async fn recheck(
+        &mut self,
+        info: &TorrentInfo,
+        have: &Bitfield,
+        on_disk: &[bool],
+    ) -> anyhow::Result<Bitfield> {
+        let mut checked = Bitfield::new(self.layout.piece_count);
+        for index in have.iter() {
+            let length = self.layout.piece_len(index);
+            let suspect = self
+                .layout
+                .spans(index, 0, length as usize)
+                .iter()
+                .any(|span| !on_disk[span.file]);
+            if suspect && !info.verify_piece(index, &self.read_block(index, 0, length).await?) {
+                continue;
+            }
+            checked.set(index);
+        }
+        Ok(checked)
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        for file in &mut self.handles {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Verified pieces of a torrent, shared between every peer connection.
+///
+/// Downloaded pieces are written through the store, which records them as available and
+/// announces them so that connections can tell their peers with `Have`. Blocks are only ever
+/// served from pieces the store knows to be verified.
+#[derive(Debug)]
+pub struct PieceStore {
+    layout: Layout,
+    storage: tokio::sync::Mutex<Storage>,
+    have: Mutex<Bitfield>,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    announce: broadcast::Sender<u32>,
+}
+
+impl PieceStore {
+    /// Wrap `storage`, which already holds the verified pieces in `have`.
+    pub fn new(storage: Storage, have: Bitfield) -> Self {
+        Self {
+            layout: storage.layout.clone(),
+            storage: tokio::sync::Mutex::new(storage),
+            have: Mutex::new(have),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            announce: broadcast::channel(1024).0,
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn have(&self) -> Bitfield {
+        self.have.lock().unwrap().clone()
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.have.lock().unwrap().has(index)
+    }
+
+    /// Total bytes served to peers
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes of verified pieces downloaded from peers
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the torrent we don't have yet
+    pub fn left(&self) -> u64 {
+        let have = self.have.lock().unwrap();
+        have.missing()
+            .map(|index| u64::from(self.layout.piece_len(index)))
+            .sum()
+    }
+
+    /// Receive the index of every piece that becomes available from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.announce.subscribe()
+    }
+
+    /// Store a whole piece which has already been verified.
+    pub async fn write_piece(&self, index: u32, piece: &[u8]) -> anyhow::Result<()> {
+        self.storage
+            .lock()
+            .await
+            .write_block(index, 0, piece)
+            .await
+            .with_context(|| format!("writing piece {}", index))?;
+        self.have.lock().unwrap().set(index);
+        self.downloaded
+            .fetch_add(piece.len() as u64, Ordering::Relaxed);
+        // nobody listening is fine
+        let _ = self.announce.send(index);
+        Ok(())
+    }
+
+    /// Read a block for a peer, refusing anything outside the pieces we have verified.
+    pub async fn read_block(&self, index: u32, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        ensure!(self.has(index), "piece {} is not available", index);
+        ensure!(
+            begin as u64 + length as u64 <= self.layout.piece_len(index) as u64,
+            "block at {}+{} is outside piece {}",
+            begin,
+            length,
+            index
+        );
+        let block = self
+            .storage
+            .lock()
+            .await
+            .read_block(index, begin, length)
+            .await?;
+        self.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+        Ok(block)
+    }
+
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        self.storage.lock().await.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::TorrentFile;
+
+    /// A multi-file torrent of files with `lengths` named after their index.
+    fn info(piece_length: u32, lengths: &[u64]) -> TorrentInfo {
+        let total: u64 = lengths.iter().sum();
+        TorrentInfo {
+            name: "dir".to_string(),
+            piece_length,
+            pieces: vec![0; total.div_ceil(piece_length.into()) as usize * 20],
+            files: FileLayout::Multi {
+                files: lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &length)| TorrentFile {
+                        length,
+                        path: vec![i.to_string()],
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn span(file: usize, offset: u64, start: usize, end: usize) -> Span {
+        Span {
+            file,
+            offset,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn pieces_are_split_across_files() {
+        let layout = Layout::new(&info(8, &[5, 0, 10, 3]), "out").unwrap();
+        assert_eq!(layout.total_length(), 18);
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.files()[2].path, Path::new("out").join("2"));
+        assert_eq!(layout.files()[3].offset, 15);
+
+        // the empty file between the first two never gets a span
+        assert_eq!(layout.spans(0, 0, 8), [span(0, 0, 0, 5), span(2, 0, 5, 8)]);
+        assert_eq!(layout.spans(0, 4, 4), [span(0, 4, 0, 1), span(2, 0, 1, 4)]);
+        assert_eq!(layout.spans(1, 0, 8), [span(2, 3, 0, 7), span(3, 0, 7, 8)]);
+        assert_eq!(layout.spans(2, 0, 2), [span(3, 1, 0, 2)]);
+    }
+
+    #[test]
+    fn the_last_piece_may_be_short() {
+        let layout = Layout::new(&info(8, &[5, 0, 10, 3]), "out").unwrap();
+        assert_eq!(layout.piece_len(0), 8);
+        assert_eq!(layout.piece_len(1), 8);
+        assert_eq!(layout.piece_len(2), 2);
+
+        let exact = Layout::new(&info(8, &[16]), "out").unwrap();
+        assert_eq!(exact.piece_len(1), 8);
+    }
+
+    #[test]
+    fn piece_count_must_match_length() {
+        let mut info = info(8, &[16]);
+        info.pieces.extend([0; 20]);
+        assert!(Layout::new(&info, "out").is_err());
+    }
+
+    #[tokio::test]
+    async fn pieces_in_missing_or_short_files_are_rechecked() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (1..=18).collect();
+        let mut info = info(8, &[5, 10, 3]);
+        info.pieces = data.chunks(8).flat_map(Sha1::digest).collect();
+        let layout = Layout::new(&info, dir.path()).unwrap();
+        for (entry, content) in layout
+            .files()
+            .iter()
+            .zip([&data[..5], &data[5..15], &data[15..]])
+        {
+            std::fs::write(&entry.path, content).unwrap();
+        }
+        assert_eq!(layout.files_on_disk(), [true, true, true]);
+
+        // the middle file holds the end of the first piece, which survives, and most of the second
+        std::fs::write(&layout.files()[1].path, &data[5..10]).unwrap();
+        let on_disk = layout.files_on_disk();
+        assert_eq!(on_disk, [true, false, true]);
+        let mut storage = Storage::open(layout).await.unwrap();
+        let have = storage
+            .recheck(&info, &Bitfield::full(3), &on_disk)
+            .await
+            .unwrap();
+        assert_eq!(have.iter().collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[test]
+    fn paths_must_stay_inside_the_download() {
+        let path = |components: &[&str]| {
+            sanitize_path(&components.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(path(&["a", "b.txt"]).unwrap(), Path::new("a").join("b.txt"));
+        for bad in [
+            &[][..],
+            &[".."],
+            &["a", ".."],
+            &["/etc"],
+            &["a/b"],
+            &["."],
+            &[""],
+        ] {
+            assert!(path(bad).is_err(), "{:?} was accepted", bad);
+        }
+
+        let mut info = info(8, &[8]);
+        let FileLayout::Multi { files } = &mut info.files else {
+            unreachable!();
+        };
+        files[0].path = vec!["..".to_string(), "escaped".to_string()];
+        assert!(Layout::new(&info, "out").is_err());
+    }
+}
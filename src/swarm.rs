@@ -0,0 +1,530 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, Notify},
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    bitfield::Bitfield,
+    extension::Registry,
+    magnet::MetadataServer,
+    peer::{BadBlock, Client, Handshake},
+    pex::{PeerSet, Pex, FLAG_REACHABLE},
+    resume::ResumeFile,
+    storage::PieceStore,
+    Torrent,
+};
+
+/// How long to wait for a TCP connection and handshake with a peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer which takes longer than this to deliver a whole piece is considered stalled
+const PIECE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a choked peer is given to unchoke us again before we give up downloading from it
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often download progress is written to the resume file
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a download with no peers left waits for one to connect to us before giving up
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Number of pieces a peer may fail hash verification on before it is banned
+const MAX_HASH_FAILURES: u32 = 3;
+
+/// Newly discovered peers are only connected to while we have fewer connections
+/// than this
+const MAX_PEERS: usize = 50;
+
+/// Most distinct peers a download tries to connect to, so that the addresses other peers hand
+/// us can't grow without bound
+const MAX_KNOWN_PEERS: usize = 10_000;
+
+/// Pieces which still need to be downloaded, shared between every peer connection.
+#[derive(Debug, Default)]
+struct PieceQueue {
+    pending: BTreeSet<u32>,
+    in_flight: HashMap<u32, SocketAddr>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<PieceQueue>,
+    /// Signalled whenever a piece is put back into `pending`
+    returned: Notify,
+    /// Number of corrupt pieces received from each peer
+    hash_failures: Mutex<HashMap<IpAddr, u32>>,
+    /// Pieces which have been verified and written to storage
+    store: Arc<PieceStore>,
+    /// Connected peers, as shared with peer exchange
+    peers: Arc<PeerSet>,
+}
+
+impl Shared {
+    /// Claim a pending piece that `client` has, or `None` once there is nothing left at all.
+    ///
+    /// While there is nothing to claim the peer's messages are still handled, so it can announce
+    /// pieces which make it worth claiming one and download from us in the meantime.
+    async fn claim(&self, addr: SocketAddr, client: &mut Client) -> anyhow::Result<Option<u32>> {
+        loop {
+            // register interest before checking so a piece returned in between isn't missed
+            let returned = self.returned.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                let piece = queue
+                    .pending
+                    .iter()
+                    .copied()
+                    .find(|&index| client.has_piece(index));
+                if let Some(index) = piece {
+                    queue.pending.remove(&index);
+                    queue.in_flight.insert(index, addr);
+                    return Ok(Some(index));
+                }
+                if queue.pending.is_empty() && queue.in_flight.is_empty() {
+                    return Ok(None);
+                }
+            }
+            client.handle_next(returned).await?;
+        }
+    }
+
+    /// Hand a piece back so that another peer can pick it up.
+    fn release(&self, index: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.in_flight.remove(&index);
+        queue.pending.insert(index);
+        self.returned.notify_waiters();
+    }
+
+    /// Record that `addr` sent data for a piece which failed verification, returning whether it
+    /// should now be banned.
+    fn record_hash_failure(&self, addr: SocketAddr) -> bool {
+        let mut failures = self.hash_failures.lock().unwrap();
+        let count = failures.entry(addr.ip()).or_default();
+        *count += 1;
+        *count >= MAX_HASH_FAILURES
+    }
+
+    /// Ban `addr` straight away, as if it had sent [`MAX_HASH_FAILURES`] corrupt pieces.
+    fn ban(&self, addr: SocketAddr) {
+        self.hash_failures
+            .lock()
+            .unwrap()
+            .insert(addr.ip(), MAX_HASH_FAILURES);
+    }
+
+    fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.hash_failures
+            .lock()
+            .unwrap()
+            .get(&addr.ip())
+            .is_some_and(|&count| count >= MAX_HASH_FAILURES)
+    }
+
+    fn complete(&self, index: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.in_flight.remove(&index);
+        if queue.pending.is_empty() && queue.in_flight.is_empty() {
+            // wake idle peers so they notice there is nothing left
+            self.returned.notify_waiters();
+        }
+    }
+}
+
+/// Downloads a torrent from many peers at once.
+///
+/// Every peer gets its own connection task which repeatedly claims a piece it has from a shared
+/// queue. Pieces are handed back to the queue if the peer chokes us, disconnects or stalls, so
+/// another peer can pick them up.
+#[derive(Debug)]
+pub struct Swarm {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    shared: Arc<Shared>,
+    /// The port we accept connections on, advertised to peers
+    port: Option<u16>,
+    /// Connections accepted by a listener, handed over through [`Inbound`]
+    incoming: mpsc::Sender<Client>,
+    incoming_rx: Mutex<Option<mpsc::Receiver<Client>>>,
+    /// Peers learned through peer exchange or [`Swarm::add_peer`]
+    discovered_rx: Mutex<Option<mpsc::Receiver<SocketAddr>>>,
+}
+
+impl Swarm {
+    /// Create a swarm that will download every piece not already in `store`.
+    pub fn new(torrent: Torrent, info_hash: [u8; 20], store: Arc<PieceStore>) -> Self {
+        let (incoming, incoming_rx) = mpsc::channel(16);
+        let (peers, discovered_rx) = PeerSet::new();
+        Self {
+            torrent,
+            info_hash,
+            shared: Arc::new(Shared {
+                queue: Mutex::new(PieceQueue {
+                    pending: store.have().missing().collect(),
+                    in_flight: HashMap::new(),
+                }),
+                returned: Notify::new(),
+                hash_failures: Mutex::new(HashMap::new()),
+                store,
+                peers,
+            }),
+            port: None,
+            incoming,
+            incoming_rx: Mutex::new(Some(incoming_rx)),
+            discovered_rx: Mutex::new(Some(discovered_rx)),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Tell peers we accept connections on `port`.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = Some(port);
+    }
+
+    /// Connect to `addr` while downloading, unless it is already known.
+    pub fn add_peer(&self, addr: SocketAddr) {
+        self.shared.peers.discover(addr);
+    }
+
+    /// The pieces which have been downloaded and verified so far
+    pub fn have(&self) -> Bitfield {
+        self.shared.store.have()
+    }
+
+    /// A handle for passing connections from other peers into this swarm.
+    pub fn inbound(&self) -> Inbound {
+        Inbound {
+            torrent: self.torrent.clone(),
+            store: Arc::clone(&self.shared.store),
+            peers: Arc::clone(&self.shared.peers),
+            port: self.port,
+            tx: self.incoming.clone(),
+        }
+    }
+
+    /// Whether a listener may still hand us new peers through an [`Inbound`]
+    fn listening(&self) -> bool {
+        self.incoming.strong_count() > 1
+    }
+
+    /// Connect to every peer in `peers` and download all pending pieces, also taking on any peers
+    /// which connect to us through an [`Inbound`].
+    ///
+    /// Progress is periodically recorded in `resume`, if given. With `seed`, connections are kept
+    /// open once the download is complete to upload to the peers, and this only returns once
+    /// every peer has gone away and nobody is listening for more. An unfinished download fails
+    /// once it has had no peers for [`IDLE_TIMEOUT`], even while listening.
+    pub async fn download(
+        &self,
+        peers: &[SocketAddr],
+        resume: Option<&ResumeFile>,
+        seed: bool,
+    ) -> anyhow::Result<()> {
+        let mut incoming = self
+            .incoming_rx
+            .lock()
+            .unwrap()
+            .take()
+            .context("swarm is already downloading")?;
+        let mut discovered = self.discovered_rx.lock().unwrap().take();
+        let mut remaining = self.shared.queue.lock().unwrap().pending.len();
+        let (tx, mut rx) = mpsc::channel(peers.len().max(1));
+
+        let mut set = JoinSet::new();
+        let mut known = HashSet::new();
+        for &addr in peers {
+            if self.shared.is_banned(addr) {
+                eprintln!("skipping banned peer {}", addr);
+                continue;
+            }
+            if known.insert(addr) {
+                self.connect(&mut set, addr, &tx, seed);
+            }
+        }
+
+        let store = &self.shared.store;
+        let mut last_save = Instant::now();
+        // since when the download has had no peers
+        let mut idle_since = (set.is_empty() && remaining > 0).then(Instant::now);
+        while remaining > 0 || seed {
+            tokio::select! {
+                Some(index) = rx.recv() => {
+                    remaining -= 1;
+                    eprintln!("received piece {} ({} remaining)", index, remaining);
+
+                    if let Some(resume) = resume {
+                        if remaining == 0 || last_save.elapsed() >= RESUME_SAVE_INTERVAL {
+                            // data must be on disk before the resume file claims it is
+                            store.flush().await?;
+                            resume.save(&store.have()).await?;
+                            last_save = Instant::now();
+                        }
+                    }
+                    if remaining == 0 && seed {
+                        eprintln!("download complete, seeding");
+                    }
+                }
+                Some(client) = incoming.recv() => {
+                    let addr = client.addr();
+                    if self.shared.is_banned(addr) {
+                        eprintln!("rejecting banned peer {}", addr);
+                        continue;
+                    }
+                    eprintln!("accepted peer {}", addr);
+                    let shared = Arc::clone(&self.shared);
+                    let tx = tx.clone();
+                    set.spawn(async move {
+                        if let Err(e) = run_peer(client, &shared, tx, seed).await {
+                            eprintln!("peer {} dropped: {:#}", addr, e);
+                        }
+                    });
+                }
+                Some(addr) = recv_discovered(&mut discovered) => {
+                    if set.len() >= MAX_PEERS
+                        || known.len() >= MAX_KNOWN_PEERS
+                        || self.shared.is_banned(addr)
+                        || !known.insert(addr)
+                    {
+                        continue;
+                    }
+                    eprintln!("connecting to newly discovered peer {}", addr);
+                    self.connect(&mut set, addr, &tx, seed);
+                }
+                Some(_) = set.join_next() => {}
+                () = sleep_until(idle_since.map(|since| since + IDLE_TIMEOUT)) => {}
+            }
+
+            if !set.is_empty() || remaining == 0 {
+                idle_since = None;
+                if set.is_empty() && !self.listening() {
+                    break;
+                }
+                continue;
+            }
+            // a listener may still hand us peers, but don't wait for them forever
+            let since = *idle_since.get_or_insert_with(Instant::now);
+            if self.listening() && since.elapsed() < IDLE_TIMEOUT {
+                continue;
+            }
+            if let Some(resume) = resume {
+                store.flush().await?;
+                resume.save(&store.have()).await?;
+            }
+            bail!("all peers disconnected with {} pieces remaining", remaining);
+        }
+
+        set.abort_all();
+        Ok(())
+    }
+
+    /// Spawn a task in `set` which connects to the peer at `addr` and downloads from it.
+    fn connect(&self, set: &mut JoinSet<()>, addr: SocketAddr, tx: &mpsc::Sender<u32>, seed: bool) {
+        let torrent = self.torrent.clone();
+        let info_hash = self.info_hash;
+        let extensions = extensions(&torrent, self.port, &self.shared.peers, addr);
+        let shared = Arc::clone(&self.shared);
+        let tx = tx.clone();
+        set.spawn(async move {
+            let res = async {
+                let store = Some(Arc::clone(&shared.store));
+                let client = timeout(
+                    CONNECT_TIMEOUT,
+                    Client::connect(addr, torrent, info_hash, store, extensions),
+                )
+                .await
+                .context("timed out connecting")??;
+                run_peer(client, &shared, tx, seed).await
+            };
+            if let Err(e) = res.await {
+                eprintln!("peer {} dropped: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+/// Receive the next newly discovered peer, waiting forever once there are none.
+async fn recv_discovered(
+    discovered: &mut Option<mpsc::Receiver<SocketAddr>>,
+) -> Option<SocketAddr> {
+    match discovered {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Passes connections accepted elsewhere, such as by a [`crate::listener::Listener`], into a
+/// [`Swarm`].
+#[derive(Debug, Clone)]
+pub struct Inbound {
+    torrent: Torrent,
+    store: Arc<PieceStore>,
+    peers: Arc<PeerSet>,
+    port: Option<u16>,
+    tx: mpsc::Sender<Client>,
+}
+
+impl Inbound {
+    /// Finish the handshake with a peer which has sent `theirs`, and hand it to the swarm.
+    pub async fn accept(&self, stream: TcpStream, theirs: Handshake) -> anyhow::Result<()> {
+        let addr = stream.peer_addr()?;
+        let client = Client::accept(
+            stream,
+            theirs,
+            self.torrent.clone(),
+            Some(Arc::clone(&self.store)),
+            extensions(&self.torrent, self.port, &self.peers, addr),
+        )
+        .await?;
+        self.tx
+            .send(client)
+            .await
+            .ok()
+            .context("swarm is no longer running")
+    }
+}
+
+/// The extensions enabled on every connection for `torrent`, here with the peer at `addr`.
+fn extensions(
+    torrent: &Torrent,
+    port: Option<u16>,
+    peers: &Arc<PeerSet>,
+    addr: SocketAddr,
+) -> Registry {
+    let mut extensions = Registry::new().with(Pex::new(Arc::clone(peers), addr));
+    if let Some(port) = port {
+        extensions = extensions.with_port(port);
+    }
+    if !torrent.info_bytes.is_empty() {
+        extensions = extensions.with(MetadataServer::new(torrent.info_bytes.clone()));
+    }
+    extensions
+}
+
+/// Drive a single peer connection until there is nothing left to download or the peer fails,
+/// sharing it with other peers through peer exchange in the meantime.
+async fn run_peer(
+    client: Client,
+    shared: &Shared,
+    tx: mpsc::Sender<u32>,
+    seed: bool,
+) -> anyhow::Result<()> {
+    let listen_addr = client.listen_addr();
+    if let Some(listen_addr) = listen_addr {
+        // we could only have connected to the peer ourselves if it accepts connections
+        let flags = if listen_addr == client.addr() {
+            FLAG_REACHABLE
+        } else {
+            0
+        };
+        shared.peers.insert(listen_addr, flags);
+    }
+    let res = exchange_pieces(client, shared, tx, seed).await;
+    if let Some(listen_addr) = listen_addr {
+        shared.peers.remove(listen_addr);
+    }
+    res
+}
+
+async fn exchange_pieces(
+    mut client: Client,
+    shared: &Shared,
+    tx: mpsc::Sender<u32>,
+    seed: bool,
+) -> anyhow::Result<()> {
+    let addr = client.addr();
+    let store = Arc::clone(&shared.store);
+
+    let mut downloading = !store.have().is_complete();
+    if downloading {
+        client.interested().await?;
+        downloading = wait_unchoke(&mut client, seed).await?;
+    }
+
+    while downloading {
+        let Some(index) = shared.claim(addr, &mut client).await? else {
+            break;
+        };
+        let length = store.layout().piece_len(index);
+        match timeout(PIECE_TIMEOUT, client.download_piece(index, length)).await {
+            Ok(Ok(Some(piece))) if !client.torrent().info.verify_piece(index, &piece) => {
+                shared.release(index);
+                eprintln!("piece {} from {} failed hash check", index, addr);
+                if shared.record_hash_failure(addr) {
+                    bail!("banned after {} corrupt pieces", MAX_HASH_FAILURES);
+                }
+            }
+            Ok(Ok(Some(piece))) => {
+                if let Err(e) = store.write_piece(index, &piece).await {
+                    shared.release(index);
+                    return Err(e);
+                }
+                shared.complete(index);
+                if tx.send(index).await.is_err() {
+                    // the download was abandoned
+                    return Ok(());
+                }
+            }
+            Ok(Ok(None)) => {
+                shared.release(index);
+                downloading = wait_unchoke(&mut client, seed).await?;
+            }
+            Ok(Err(e)) => {
+                shared.release(index);
+                if e.is::<BadBlock>() {
+                    shared.ban(addr);
+                    return Err(e.context("banned"));
+                }
+                return Err(e);
+            }
+            Err(_) => {
+                shared.release(index);
+                bail!("stalled downloading piece {}", index);
+            }
+        }
+    }
+
+    if seed {
+        client.serve().await?;
+    }
+    Ok(())
+}
+
+/// Wait for `client` to unchoke us, returning whether it did within [`UNCHOKE_TIMEOUT`].
+///
+/// A peer which doesn't is only worth keeping to upload to with `seed`, and fails otherwise.
+async fn wait_unchoke(client: &mut Client, seed: bool) -> anyhow::Result<bool> {
+    let deadline = Instant::now() + UNCHOKE_TIMEOUT;
+    while client.is_choked() {
+        if Instant::now() >= deadline {
+            ensure!(seed, "timed out waiting to be unchoked");
+            eprintln!("{} won't unchoke us, only uploading to it", client.addr());
+            return Ok(false);
+        }
+        client
+            .handle_next(tokio::time::sleep_until(deadline.into()))
+            .await?;
+    }
+    Ok(true)
+}
@@ -0,0 +1,419 @@
+//! Announcing to trackers over HTTP or UDP.
+
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::OnceLock, time::Duration};
+
+use anyhow::{bail, ensure, Context};
+use rand::seq::SliceRandom;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    decode::{decode, from_decoded, Decoded, StreamParser, Value},
+    peer, PeersResponse, Torrent,
+};
+
+mod server;
+mod session;
+mod udp;
+
+pub use server::Server;
+pub use session::Session;
+
+/// Number of peers we ask trackers for
+const NUMWANT: u32 = 50;
+
+/// HTTP responses larger than this are refused rather than buffered
+const MAX_RESPONSE_SIZE: usize = 1 << 22;
+
+/// How long an HTTP tracker gets to send its whole response, after which it counts as failed
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A tracker refused our request, giving `reason`.
+///
+/// Returned inside the `anyhow::Error` of a failed announce or scrape so callers can tell a
+/// tracker which answered apart from one which couldn't be reached.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("tracker refused request: {reason}")]
+pub struct Failure {
+    pub reason: String,
+}
+
+/// Swarm statistics a tracker keeps for a torrent
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent
+    pub seeders: u32,
+    /// Number of times a download of the torrent has completed
+    pub completed: u32,
+    /// Peers still downloading
+    pub leechers: u32,
+}
+
+/// Announces other than the regular ones a client sends while it is running.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The first announce for a download
+    Started,
+    /// The download has just finished
+    Completed,
+    /// We are shutting down
+    Stopped,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// Random key identifying us to trackers across changes of IP address, fixed for the lifetime
+/// of the process.
+pub fn key() -> u32 {
+    static KEY: OnceLock<u32> = OnceLock::new();
+    *KEY.get_or_init(rand::random)
+}
+
+/// The client all HTTP tracker requests are sent with, so that none can stall forever.
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .expect("building HTTP client")
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    /// The port we accept connections on
+    pub port: u16,
+    /// Total bytes uploaded since the `started` event
+    pub uploaded: u64,
+    /// Total bytes downloaded since the `started` event
+    pub downloaded: u64,
+    /// Bytes we still need to complete the torrent
+    pub left: u64,
+    pub event: Option<Event>,
+    /// Number of peers we would like to hear about
+    pub numwant: u32,
+    /// Sent back to a tracker which handed one out on an earlier announce
+    pub tracker_id: Option<String>,
+}
+
+impl AnnounceRequest {
+    /// A regular announce which has transferred nothing yet.
+    pub fn new(info_hash: [u8; 20], port: u16, left: u64) -> Self {
+        Self {
+            info_hash,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            numwant: NUMWANT,
+            tracker_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// How long to wait before announcing again
+    pub interval: Duration,
+    /// Announcing more often than this is not allowed
+    pub min_interval: Option<Duration>,
+    /// To be sent with every later announce to the same tracker
+    pub tracker_id: Option<String>,
+    /// A message to show the user even though the announce succeeded
+    pub warning: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// A torrent's trackers, grouped into tiers which are tried in order (BEP 12).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    /// Tracker ids handed out by each tracker
+    tracker_ids: HashMap<String, String>,
+}
+
+impl TrackerList {
+    /// The trackers of `torrent`, from `announce-list` if it has one or `announce` otherwise.
+    ///
+    /// The trackers within each tier are shuffled, as the spec asks.
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut tiers = if torrent.announce_list.iter().any(|tier| !tier.is_empty()) {
+            torrent.announce_list.clone()
+        } else if !torrent.announce.is_empty() {
+            vec![vec![torrent.announce.clone()]]
+        } else {
+            Vec::new()
+        };
+        tiers.retain(|tier| !tier.is_empty());
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Announce to each tracker in turn until one responds, returning its response.
+    ///
+    /// The tracker which responded is moved to the front of its tier so it is tried first next
+    /// time.
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                let mut request = request.clone();
+                request.tracker_id = self.tracker_ids.get(&tier[i]).cloned();
+                match announce(&tier[i], &request).await {
+                    Ok(response) => {
+                        let url = tier.remove(i);
+                        if let Some(warning) = &response.warning {
+                            eprintln!("tracker {} warns: {}", url, warning);
+                        }
+                        if let Some(id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), id.clone());
+                        }
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("tracker {} failed: {:#}", tier[i], e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.context("every tracker failed")),
+            None => bail!("torrent has no trackers"),
+        }
+    }
+}
+
+/// Send `request` to the tracker at `url`.
+///
+/// The protocol is picked from the URL scheme.
+pub async fn announce(url: &str, request: &AnnounceRequest) -> anyhow::Result<AnnounceResponse> {
+    let url = Url::from_str(url).with_context(|| format!("invalid tracker URL {:?}", url))?;
+    match url.scheme() {
+        "http" | "https" => announce_http(url, request).await,
+        "udp" => udp::announce(&url, request).await,
+        scheme => bail!("unsupported tracker protocol {:?}", scheme),
+    }
+}
+
+/// Ask the tracker with announce URL `url` for statistics on each torrent in `info_hashes`, in
+/// order.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+    let url = Url::from_str(url).with_context(|| format!("invalid tracker URL {:?}", url))?;
+    match url.scheme() {
+        "http" | "https" => scrape_http(scrape_url(&url)?, info_hashes).await,
+        "udp" => udp::scrape(&url, info_hashes).await,
+        scheme => bail!("scraping is not supported for {:?} trackers", scheme),
+    }
+}
+
+/// The scrape URL of an HTTP tracker, which by convention is its announce URL with `announce`
+/// at the start of the last path segment replaced by `scrape`.
+pub fn scrape_url(announce: &Url) -> anyhow::Result<Url> {
+    let path = announce.path();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", path));
+    let rest = last
+        .strip_prefix("announce")
+        .with_context(|| format!("tracker {} does not support scraping", announce))?;
+    let mut url = announce.clone();
+    url.set_path(&format!("{}/scrape{}", dir, rest));
+    Ok(url)
+}
+
+/// Statistics for one torrent in an HTTP scrape response
+#[derive(Debug, Clone, Default, Deserialize)]
+struct FileStats {
+    #[serde(default)]
+    complete: u32,
+    #[serde(default)]
+    downloaded: u32,
+    #[serde(default)]
+    incomplete: u32,
+}
+
+async fn scrape_http(mut url: Url, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+    for info_hash in info_hashes {
+        append_bytes(&mut url, "info_hash", info_hash);
+    }
+    let text = read_body(http_client().get(url).send().await?).await?;
+    let files = parse_scrape(&text).context("parsing scrape response")?;
+    // trackers leave out torrents they know nothing about
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            let stats = files.get(&info_hash[..]).cloned().unwrap_or_default();
+            ScrapeStats {
+                seeders: stats.complete,
+                completed: stats.downloaded,
+                leechers: stats.incomplete,
+            }
+        })
+        .collect())
+}
+
+/// Add `key` to the query of `url` with the raw bytes `value`, which needn't be UTF-8 and so
+/// can't go through [`Url::query_pairs_mut`].
+fn append_bytes(url: &mut Url, key: &str, value: &[u8]) {
+    let pair = format!("{}={}", key, percent_encode(value));
+    let query = match url.query() {
+        Some(query) if !query.is_empty() => format!("{}&{}", query, pair),
+        _ => pair,
+    };
+    url.set_query(Some(&query));
+}
+
+/// Percent-encode every byte of `bytes` other than the unreserved characters of RFC 3986.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Read the bencoded body of an HTTP tracker response.
+///
+/// The body is checked as it arrives, so anything else (such as an HTML error page) is refused
+/// without waiting for all of it.
+async fn read_body(mut res: reqwest::Response) -> anyhow::Result<Vec<u8>> {
+    let mut parser = StreamParser::new();
+    let mut body = Vec::new();
+    while !parser.is_done() {
+        let Some(chunk) = res.chunk().await? else {
+            parser
+                .end_of_input()
+                .context("tracker response ended early")?;
+            break;
+        };
+        let mut rest = &chunk[..];
+        while !rest.is_empty() && !parser.is_done() {
+            let (consumed, _) = parser.parse(rest).context("decoding tracker response")?;
+            rest = &rest[consumed..];
+        }
+        body.extend_from_slice(&chunk[..chunk.len() - rest.len()]);
+        ensure!(
+            body.len() <= MAX_RESPONSE_SIZE,
+            "tracker response is larger than {} bytes",
+            MAX_RESPONSE_SIZE
+        );
+    }
+    Ok(body)
+}
+
+/// Pick the statistics for each torrent out of an HTTP scrape response, keyed by info hash.
+fn parse_scrape(body: &[u8]) -> anyhow::Result<HashMap<Vec<u8>, FileStats>> {
+    let response = Value::decode(body)?;
+    ensure!(response.as_dict().is_some(), "not a dictionary");
+    if let Some(reason) = response.get("failure reason") {
+        return Err(Failure {
+            reason: reason.to_string(),
+        }
+        .into());
+    }
+    let Some(files) = response.get("files") else {
+        return Ok(HashMap::new());
+    };
+    files
+        .as_dict()
+        .context("`files` is not a dictionary")?
+        .iter()
+        .map(|(info_hash, stats)| {
+            let stats =
+                from_decoded(&Decoded::from(stats)).context("parsing torrent statistics")?;
+            Ok((info_hash.clone(), stats))
+        })
+        .collect()
+}
+
+async fn announce_http(
+    mut url: Url,
+    request: &AnnounceRequest,
+) -> anyhow::Result<AnnounceResponse> {
+    let peer_id = peer::peer_id();
+    append_bytes(&mut url, "info_hash", &request.info_hash);
+    append_bytes(&mut url, "peer_id", &peer_id);
+    {
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("port", &request.port.to_string())
+            .append_pair("uploaded", &request.uploaded.to_string())
+            .append_pair("downloaded", &request.downloaded.to_string())
+            .append_pair("left", &request.left.to_string())
+            .append_pair("compact", "1")
+            .append_pair("numwant", &request.numwant.to_string())
+            .append_pair("key", &format!("{:08x}", key()));
+        if let Some(event) = request.event {
+            query.append_pair("event", event.as_str());
+        }
+        if let Some(id) = &request.tracker_id {
+            query.append_pair("trackerid", id);
+        }
+    }
+    let text = read_body(http_client().get(url).send().await?).await?;
+    let (_, res) = decode(&text).context("decoding tracker response")?;
+    let res: PeersResponse = from_decoded(&res).context("parsing tracker response")?;
+    if let Some(reason) = res.failure_reason {
+        return Err(Failure { reason }.into());
+    }
+
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(res.interval as u64),
+        min_interval: res.min_interval.map(|i| Duration::from_secs(i as u64)),
+        tracker_id: res.tracker_id.clone(),
+        warning: res.warning_message.clone(),
+        seeders: res.complete,
+        leechers: res.incomplete,
+        peers: res.peers(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_bytes_are_percent_encoded_into_the_query() {
+        let mut url = Url::parse("http://tracker.example/announce?passkey=abc").unwrap();
+        append_bytes(&mut url, "info_hash", &[0x00, b'a', b'~', 0xff, b' ', b'&']);
+        assert_eq!(url.query(), Some("passkey=abc&info_hash=%00a~%FF%20%26"));
+
+        let mut url = Url::parse("http://tracker.example/announce").unwrap();
+        append_bytes(&mut url, "info_hash", b"\x12\x34");
+        url.query_pairs_mut().append_pair("port", "6881");
+        assert_eq!(
+            url.as_str(),
+            "http://tracker.example/announce?info_hash=%124&port=6881"
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! A small HTTP tracker which keeps the peers of each torrent in memory.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context};
+use rand::seq::SliceRandom;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+use super::NUMWANT;
+use crate::decode::Value;
+
+/// Never hand out more peers than this in one response, whatever `numwant` says
+const MAX_NUMWANT: usize = 200;
+
+/// Requests with a longer header than this are refused
+const MAX_REQUEST_SIZE: usize = 8 << 10;
+
+/// How long a client has to send its request after connecting
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait after failing to accept a connection, such as when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    addr: SocketAddr,
+    /// Bytes the peer still needs, where 0 makes it a seeder
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Swarm {
+    /// Peers by peer id
+    peers: HashMap<[u8; 20], Peer>,
+    /// Number of `completed` events seen
+    downloaded: u32,
+}
+
+impl Swarm {
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|p| p.left == 0).count()
+    }
+
+    fn leechers(&self) -> usize {
+        self.peers.len() - self.seeders()
+    }
+
+    /// Forget peers which haven't announced within `timeout`.
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.peers
+            .retain(|_, p| now.duration_since(p.last_seen) < timeout);
+    }
+}
+
+/// An HTTP tracker serving `/announce` and `/scrape`.
+///
+/// Peers which haven't announced for twice the announce interval are forgotten, along with
+/// torrents which are left without any peers.
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    interval: Duration,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    /// When every swarm was last checked for peers to forget
+    swept: Mutex<Instant>,
+}
+
+impl Server {
+    /// Listen on `addr`, asking clients to announce every `interval`.
+    pub async fn bind(addr: SocketAddr, interval: Duration) -> anyhow::Result<Arc<Self>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("listening on {}", addr))?;
+        Ok(Arc::new(Self {
+            listener,
+            interval,
+            swarms: Mutex::default(),
+            swept: Mutex::new(Instant::now()),
+        }))
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Peers which miss an announce are given a second chance before being forgotten.
+    fn peer_timeout(&self) -> Duration {
+        self.interval * 2
+    }
+
+    /// Forget peers which stopped announcing, and the torrents left without any peers.
+    fn expire(&self, swarms: &mut HashMap<[u8; 20], Swarm>, now: Instant) {
+        swarms.retain(|_, swarm| {
+            swarm.expire(now, self.peer_timeout());
+            !swarm.peers.is_empty()
+        });
+        *self.swept.lock().unwrap() = now;
+    }
+
+    /// Serve requests forever, each connection in its own task.
+    pub async fn run(self: &Arc<Self>) {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("accepting tracker client: {}", e);
+                    sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let server = Arc::clone(self);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(stream, addr).await {
+                    eprintln!("tracker client {} dropped: {:#}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Answer the single request sent on `stream`.
+    async fn handle(&self, mut stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let target = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+            .await
+            .context("timed out waiting for request")??;
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query = parse_query(query);
+        let (status, body) = match path {
+            "/announce" => {
+                let body = self
+                    .announce(&query, addr.ip().to_canonical())
+                    .unwrap_or_else(|e| failure(&format!("{:#}", e)));
+                ("200 OK", body)
+            }
+            "/scrape" => ("200 OK", self.scrape(&query)),
+            _ => ("404 Not Found", b"not found".to_vec()),
+        };
+
+        let mut response = Vec::new();
+        write!(
+            response,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )?;
+        response.extend_from_slice(&body);
+        stream.write_all(&response).await?;
+        Ok(())
+    }
+
+    /// Record the announce described by `query` from a client at `ip` and list the other peers
+    /// of the torrent for it.
+    fn announce(&self, query: &[(String, Vec<u8>)], ip: IpAddr) -> anyhow::Result<Vec<u8>> {
+        let info_hash: [u8; 20] = param(query, "info_hash")
+            .context("missing info_hash")?
+            .try_into()
+            .ok()
+            .context("info_hash must be 20 bytes")?;
+        let peer_id: [u8; 20] = param(query, "peer_id")
+            .context("missing peer_id")?
+            .try_into()
+            .ok()
+            .context("peer_id must be 20 bytes")?;
+        let port: u16 = number(query, "port")?.context("missing port")?;
+        ensure!(port != 0, "invalid port");
+        // without it a client would count as a seeder
+        let left = number(query, "left")?.context("missing left")?;
+        let numwant = number(query, "numwant")?
+            .unwrap_or(NUMWANT as usize)
+            .min(MAX_NUMWANT);
+        let compact = param(query, "compact") != Some(b"0");
+        let no_peer_id = param(query, "no_peer_id") == Some(b"1");
+        let event = param(query, "event").unwrap_or_default();
+
+        let now = Instant::now();
+        let mut swarms = self.swarms.lock().unwrap();
+        // torrents nobody announces to any more are only found by looking at all of them
+        let swept = *self.swept.lock().unwrap();
+        if now.duration_since(swept) >= self.interval {
+            self.expire(&mut swarms, now);
+        }
+        let swarm = swarms.entry(info_hash).or_default();
+        swarm.expire(now, self.peer_timeout());
+        match event {
+            b"stopped" => {
+                swarm.peers.remove(&peer_id);
+            }
+            // a client which missed our response may send `completed` again
+            b"completed" if swarm.peers.get(&peer_id).is_none_or(|p| p.left != 0) => {
+                swarm.downloaded += 1
+            }
+            _ => {}
+        }
+        if event != b"stopped" {
+            let peer = Peer {
+                addr: SocketAddr::new(ip, port),
+                left,
+                last_seen: now,
+            };
+            swarm.peers.insert(peer_id, peer);
+        }
+
+        let mut others: Vec<_> = swarm
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != peer_id)
+            .collect();
+        others.shuffle(&mut rand::thread_rng());
+        others.truncate(numwant);
+
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        let mut dicts = Vec::new();
+        for (id, peer) in &others {
+            if compact {
+                match peer.addr {
+                    SocketAddr::V4(addr) => {
+                        peers.extend_from_slice(&addr.ip().octets());
+                        peers.extend_from_slice(&addr.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(addr) => {
+                        peers6.extend_from_slice(&addr.ip().octets());
+                        peers6.extend_from_slice(&addr.port().to_be_bytes());
+                    }
+                }
+            } else {
+                dicts.push((&id[..], peer.addr.ip().to_string(), peer.addr.port()));
+            }
+        }
+
+        let mut response = Value::dict()
+            .with("interval", int(self.interval.as_secs()))
+            .with("complete", int(swarm.seeders() as u64))
+            .with("incomplete", int(swarm.leechers() as u64));
+        if compact {
+            response.insert("peers", peers);
+            if !peers6.is_empty() {
+                response.insert("peers6", peers6);
+            }
+        } else {
+            let list = dicts
+                .into_iter()
+                .map(|(id, ip, port)| {
+                    let mut dict = Value::dict().with("ip", ip).with("port", port);
+                    if !no_peer_id {
+                        dict.insert("peer id", id);
+                    }
+                    dict
+                })
+                .collect();
+            response.insert("peers", Value::List(list));
+        }
+        if swarm.peers.is_empty() {
+            swarms.remove(&info_hash);
+        }
+        let mut buf = Vec::new();
+        response.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Statistics for each torrent named in `query`, or every torrent if it names none.
+    fn scrape(&self, query: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut swarms = self.swarms.lock().unwrap();
+        self.expire(&mut swarms, Instant::now());
+        let wanted: Vec<_> = query
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .filter_map(|(_, value)| <[u8; 20]>::try_from(&value[..]).ok())
+            .collect();
+        let files: Vec<_> = if wanted.is_empty() {
+            swarms.iter().collect()
+        } else {
+            wanted
+                .iter()
+                .filter_map(|hash| swarms.get_key_value(hash))
+                .collect()
+        };
+
+        // keyed by raw info hash, where a hash asked for twice is only listed once
+        let files: Value = files
+            .into_iter()
+            .map(|(info_hash, swarm)| {
+                let stats = Value::dict()
+                    .with("complete", int(swarm.seeders() as u64))
+                    .with("downloaded", swarm.downloaded)
+                    .with("incomplete", int(swarm.leechers() as u64));
+                (info_hash.to_vec(), stats)
+            })
+            .collect();
+        let mut buf = Vec::new();
+        Value::dict()
+            .with("files", files)
+            .encode(&mut buf)
+            .expect("writing to a Vec can't fail");
+        buf
+    }
+}
+
+/// Read an HTTP request's header from `stream`, returning the request target.
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n\r\n") {
+        ensure!(buf.len() < MAX_REQUEST_SIZE, "request too large");
+        let byte = stream.read_u8().await.context("reading request")?;
+        buf.push(byte);
+    }
+    let header = String::from_utf8_lossy(&buf);
+    let line = header.lines().next().unwrap_or_default();
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line {:?}", line);
+    };
+    ensure!(method == "GET", "unsupported method {}", method);
+    Ok(target.to_string())
+}
+
+/// Split a query string into its keys and percent-decoded values.
+///
+/// Values are kept as bytes, as `info_hash` and `peer_id` need not be valid UTF-8.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                String::from_utf8_lossy(&percent_decode(key)).into_owned(),
+                percent_decode(value),
+            )
+        })
+        .collect()
+}
+
+/// Undo percent-encoding, leaving any `%` not followed by two hex digits as it is.
+fn percent_decode(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let mut byte = [0];
+        match s[i] {
+            b'%' if s.len() >= i + 3
+                && hex::decode_to_slice(&s[i + 1..i + 3], &mut byte).is_ok() =>
+            {
+                out.push(byte[0]);
+                i += 3;
+                continue;
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// The first value of `key` in `query`.
+fn param<'a>(query: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    query
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| &value[..])
+}
+
+/// The value of `key` in `query` as a number, if present.
+fn number<T: std::str::FromStr>(
+    query: &[(String, Vec<u8>)],
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    let Some(value) = param(query, key) else {
+        return Ok(None);
+    };
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Some)
+        .with_context(|| format!("invalid {}", key))
+}
+
+/// The response telling a client its announce was refused.
+fn failure(reason: &str) -> Vec<u8> {
+    let response = Value::dict().with("failure reason", reason);
+    let mut buf = Vec::new();
+    response
+        .encode(&mut buf)
+        .expect("writing to a Vec can't fail");
+    buf
+}
+
+fn int(n: u64) -> Value {
+    Value::Int(n as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode_checked, Decoded, Mode};
+
+    fn query(pairs: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_vec()))
+            .collect()
+    }
+
+    fn announce(server: &Server, info_hash: &[u8; 20], peer_id: &[u8; 20], left: &[u8]) -> Vec<u8> {
+        let query = query(&[
+            ("info_hash", info_hash),
+            ("peer_id", peer_id),
+            ("port", b"6881"),
+            ("left", left),
+        ]);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        server.announce(&query, ip).unwrap()
+    }
+
+    #[tokio::test]
+    async fn responses_are_canonical() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = Server::bind(addr, Duration::from_secs(60)).await.unwrap();
+        // hashes which aren't UTF-8 and whose order a `HashMap` won't keep
+        let hashes = [[0xff; 20], [0x00; 20], [0x80; 20]];
+        for (i, hash) in hashes.iter().enumerate() {
+            announce(&server, hash, &[i as u8; 20], b"0");
+        }
+        let body = announce(&server, &hashes[0], &[9; 20], b"100");
+        let (response, _) = decode_checked(&body, Mode::Strict).unwrap();
+        assert_eq!(response.get("interval").and_then(Decoded::as_int), Some(60));
+        assert_eq!(response.get("complete").and_then(Decoded::as_int), Some(1));
+        assert_eq!(
+            response.get("incomplete").and_then(Decoded::as_int),
+            Some(1)
+        );
+        assert_eq!(
+            response.get("peers").and_then(Decoded::as_bytes),
+            Some(&[127, 0, 0, 1, 0x1a, 0xe1][..])
+        );
+
+        let body = server.scrape(&[]);
+        let (response, _) = decode_checked(&body, Mode::Strict).unwrap();
+        let files = response.get("files").and_then(Decoded::as_dict).unwrap();
+        assert_eq!(files.len(), 3);
+        let stats = &files[&hashes[0][..]];
+        assert_eq!(stats.get("complete").and_then(Decoded::as_int), Some(1));
+        assert_eq!(stats.get("incomplete").and_then(Decoded::as_int), Some(1));
+
+        let body = server.scrape(&query(&[
+            ("info_hash", &hashes[0]),
+            ("info_hash", &hashes[1]),
+            ("info_hash", &hashes[0]),
+        ]));
+        let (response, _) = decode_checked(&body, Mode::Strict).unwrap();
+        let files = response.get("files").and_then(Decoded::as_dict).unwrap();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn swarms_are_forgotten_with_their_last_peer() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = Server::bind(addr, Duration::from_millis(20)).await.unwrap();
+        let files = |server: &Server| {
+            let body = server.scrape(&[]);
+            let (response, _) = decode_checked(&body, Mode::Strict).unwrap();
+            response
+                .get("files")
+                .and_then(Decoded::as_dict)
+                .unwrap()
+                .len()
+        };
+
+        announce(&server, &[1; 20], &[1; 20], b"0");
+        announce(&server, &[2; 20], &[2; 20], b"0");
+        assert_eq!(files(&server), 2);
+
+        // a peer which stops leaves nobody behind
+        let stopped = query(&[
+            ("info_hash", &[1; 20]),
+            ("peer_id", &[1; 20]),
+            ("port", b"6881"),
+            ("left", b"0"),
+            ("event", b"stopped"),
+        ]);
+        server
+            .announce(&stopped, IpAddr::from([127, 0, 0, 1]))
+            .unwrap();
+        assert_eq!(files(&server), 1);
+
+        // and one which misses two announces is forgotten
+        tokio::time::sleep(server.peer_timeout()).await;
+        assert_eq!(files(&server), 0);
+        assert!(server.swarms.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn announces_need_left() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = Server::bind(addr, Duration::from_secs(60)).await.unwrap();
+        let query = query(&[
+            ("info_hash", &[1; 20]),
+            ("peer_id", &[1; 20]),
+            ("port", b"6881"),
+        ]);
+        let err = server
+            .announce(&query, IpAddr::from([127, 0, 0, 1]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "missing left");
+        assert!(server.swarms.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::{
+    sync::broadcast,
+    time::{sleep_until, timeout_at},
+};
+
+use super::{AnnounceRequest, Event, TrackerList};
+use crate::storage::PieceStore;
+
+/// How long to wait before trying again when every tracker failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Never re-announce more often than this, whatever the tracker says
+const MIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the trackers get to hear that we are going away, so that shutting down never hangs
+/// on one which doesn't answer
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps the trackers up to date with a download from start to finish.
+///
+/// The first announce is `started`, followed by regular announces at the interval the tracker
+/// asks for, `completed` once the last piece is verified and finally `stopped`.
+#[derive(Debug)]
+pub struct Session {
+    trackers: TrackerList,
+    info_hash: [u8; 20],
+    port: u16,
+    store: Arc<PieceStore>,
+    /// An event which still has to reach a tracker
+    event: Option<Event>,
+    /// A tracker has heard about us, so it should hear when we stop
+    started: bool,
+    /// The download was already complete, or we have sent `completed`
+    completed: bool,
+    next_announce: Instant,
+}
+
+impl Session {
+    pub fn new(
+        trackers: TrackerList,
+        info_hash: [u8; 20],
+        port: u16,
+        store: Arc<PieceStore>,
+    ) -> Self {
+        Self {
+            trackers,
+            info_hash,
+            port,
+            completed: store.have().is_complete(),
+            store,
+            event: Some(Event::Started),
+            started: false,
+            next_announce: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    /// Send the `started` event, returning the peers the tracker knows of.
+    pub async fn start(&mut self) -> anyhow::Result<Vec<SocketAddr>> {
+        self.announce().await
+    }
+
+    /// Announce whenever the tracker wants to hear from us and as soon as the download
+    /// completes, passing every peer list to `found`. This never returns.
+    pub async fn run(&mut self, mut found: impl FnMut(Vec<SocketAddr>)) {
+        let mut haves = Some(self.store.subscribe());
+        loop {
+            tokio::select! {
+                _ = sleep_until(self.next_announce.into()) => {}
+                res = recv(&mut haves) => {
+                    if res.is_err() {
+                        haves = None;
+                    }
+                    if self.completed || !self.store.have().is_complete() {
+                        continue;
+                    }
+                    self.completed = true;
+                    self.event = Some(Event::Completed);
+                }
+            }
+            match self.announce().await {
+                Ok(peers) => found(peers),
+                Err(e) => eprintln!("announce failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Tell the trackers we are going away, sending `completed` first if that hasn't been
+    /// done.
+    ///
+    /// This gives up after [`STOP_TIMEOUT`].
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        if !self.completed && self.store.have().is_complete() {
+            self.completed = true;
+            self.event = Some(Event::Completed);
+        }
+        if self.event == Some(Event::Completed) {
+            self.announce_before(deadline).await?;
+        }
+        if self.started {
+            self.event = Some(Event::Stopped);
+            self.announce_before(deadline).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce_before(&mut self, deadline: Instant) -> anyhow::Result<Vec<SocketAddr>> {
+        timeout_at(deadline.into(), self.announce())
+            .await
+            .context("trackers did not answer in time")?
+    }
+
+    async fn announce(&mut self) -> anyhow::Result<Vec<SocketAddr>> {
+        let request = AnnounceRequest {
+            uploaded: self.store.uploaded(),
+            downloaded: self.store.downloaded(),
+            event: self.event,
+            numwant: if self.event == Some(Event::Stopped) {
+                0
+            } else {
+                super::NUMWANT
+            },
+            ..AnnounceRequest::new(self.info_hash, self.port, self.store.left())
+        };
+        let response = match self.trackers.announce(&request).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.next_announce = Instant::now() + RETRY_INTERVAL;
+                return Err(e);
+            }
+        };
+        self.started = true;
+        self.event = None;
+        let interval = response
+            .interval
+            .max(response.min_interval.unwrap_or_default())
+            .max(MIN_INTERVAL);
+        self.next_announce = Instant::now() + interval;
+        Ok(response.peers)
+    }
+}
+
+/// Wait for the next verified piece, or forever once the store has gone away.
+async fn recv(haves: &mut Option<broadcast::Receiver<u32>>) -> Result<(), ()> {
+    match haves {
+        Some(rx) => match rx.recv().await {
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => Ok(()),
+            Err(broadcast::error::RecvError::Closed) => Err(()),
+        },
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,365 @@
+//! The UDP tracker protocol (BEP 15).
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context};
+use reqwest::Url;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::timeout,
+};
+
+use super::{key, AnnounceRequest, AnnounceResponse, Event, Failure, ScrapeStats};
+use crate::peer;
+
+/// Magic constant identifying the protocol in connect requests
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id can be used for after the tracker hands it out
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// A request is retransmitted if there is no response within `RETRY_BASE * 2^n`, for `n` from
+/// 0 up to `MAX_RETRIES`. BEP 15 allows going up to 8, over an hour and a half in total, which is
+/// far longer than anyone waits for a download to start.
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 3;
+
+/// Most info hashes which fit in a single scrape request
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Connection ids by tracker address, along with when they were handed out
+fn connection_ids() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+    CONNECTION_IDS.get_or_init(Mutex::default)
+}
+
+/// Send `request` to the UDP tracker at `url`.
+pub async fn announce(url: &Url, request: &AnnounceRequest) -> anyhow::Result<AnnounceResponse> {
+    let tracker = Tracker::open(url).await?;
+
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    let mut body = Vec::with_capacity(82);
+    body.extend_from_slice(&request.info_hash);
+    body.extend_from_slice(&peer::peer_id());
+    body.extend_from_slice(&request.downloaded.to_be_bytes());
+    body.extend_from_slice(&request.left.to_be_bytes());
+    body.extend_from_slice(&request.uploaded.to_be_bytes());
+    body.extend_from_slice(&event.to_be_bytes());
+    body.extend_from_slice(&0u32.to_be_bytes()); // our IP, as seen by the tracker
+    body.extend_from_slice(&key().to_be_bytes());
+    body.extend_from_slice(&request.numwant.to_be_bytes());
+    body.extend_from_slice(&request.port.to_be_bytes());
+
+    let res = tracker.request(ACTION_ANNOUNCE, &body).await?;
+    let peers = res.get(20..).context("announce response too short")?;
+    // the tracker sends addresses of the same family as the one we reached it on
+    let peers = if tracker.addr.is_ipv4() {
+        peers
+            .chunks_exact(6)
+            .map(|chunk| {
+                let ip: [u8; 4] = chunk[..4].try_into().unwrap();
+                SocketAddr::new(Ipv4Addr::from(ip).into(), be_u16(&chunk[4..]))
+            })
+            .collect()
+    } else {
+        peers
+            .chunks_exact(18)
+            .map(|chunk| {
+                let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+                SocketAddr::new(Ipv6Addr::from(ip).into(), be_u16(&chunk[16..]))
+            })
+            .collect()
+    };
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(be_u32(&res[8..]).into()),
+        min_interval: None,
+        tracker_id: None,
+        warning: None,
+        leechers: Some(be_u32(&res[12..])),
+        seeders: Some(be_u32(&res[16..])),
+        peers,
+    })
+}
+
+/// Ask the UDP tracker at `url` for statistics on each torrent in `info_hashes`, in order.
+pub async fn scrape(url: &Url, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+    let tracker = Tracker::open(url).await?;
+    let mut stats = Vec::with_capacity(info_hashes.len());
+    for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+        let res = tracker.request(ACTION_SCRAPE, &chunk.concat()).await?;
+        let entries = res[8..].chunks_exact(12);
+        ensure!(
+            entries.len() == chunk.len(),
+            "tracker sent {} scrape results for {} torrents",
+            entries.len(),
+            chunk.len()
+        );
+        stats.extend(entries.map(|entry| ScrapeStats {
+            seeders: be_u32(&entry[0..]),
+            completed: be_u32(&entry[4..]),
+            leechers: be_u32(&entry[8..]),
+        }));
+    }
+    Ok(stats)
+}
+
+/// A socket talking to one tracker.
+#[derive(Debug)]
+struct Tracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl Tracker {
+    async fn open(url: &Url) -> anyhow::Result<Self> {
+        let host = url.host_str().context("tracker URL has no host")?;
+        let port = url.port().context("tracker URL has no port")?;
+        let addr = lookup_host((host, port))
+            .await
+            .with_context(|| format!("resolving {}", host))?
+            .next()
+            .with_context(|| format!("{} has no addresses", host))?;
+        let local: IpAddr = if addr.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("connecting to tracker {}", addr))?;
+        Ok(Self { socket, addr })
+    }
+
+    /// Send a request with `action` and `body`, returning the whole response.
+    async fn request(&self, action: u32, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut retries = 0;
+        loop {
+            let connection_id = self.connection_id(&mut retries).await?;
+            let transaction = rand::random();
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&action.to_be_bytes());
+            packet.extend_from_slice(&u32::to_be_bytes(transaction));
+            packet.extend_from_slice(body);
+
+            if let Some(res) = self.exchange(&packet, transaction, retries).await? {
+                return self.check(res, action);
+            }
+            next_retry(&mut retries)?;
+        }
+    }
+
+    /// A connection id for the tracker, reusing the last one while it is still valid.
+    async fn connection_id(&self, retries: &mut u32) -> anyhow::Result<u64> {
+        if let Some(&(id, since)) = connection_ids().lock().unwrap().get(&self.addr) {
+            if since.elapsed() < CONNECTION_ID_TTL {
+                return Ok(id);
+            }
+        }
+        loop {
+            let transaction = rand::random();
+            let mut packet = Vec::with_capacity(16);
+            packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+            packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            packet.extend_from_slice(&u32::to_be_bytes(transaction));
+
+            if let Some(res) = self.exchange(&packet, transaction, *retries).await? {
+                let res = self.check(res, ACTION_CONNECT)?;
+                let id = u64::from_be_bytes(
+                    res.get(8..16)
+                        .context("connect response too short")?
+                        .try_into()
+                        .unwrap(),
+                );
+                connection_ids()
+                    .lock()
+                    .unwrap()
+                    .insert(self.addr, (id, Instant::now()));
+                return Ok(id);
+            }
+            next_retry(retries)?;
+        }
+    }
+
+    /// Send `packet` and wait for the response to `transaction`, or `None` on timeout.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        transaction: u32,
+        retries: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        self.socket
+            .send(packet)
+            .await
+            .context("sending to tracker")?;
+        let wait = RETRY_BASE * 2u32.pow(retries);
+        match timeout(wait, self.recv(transaction)).await {
+            Ok(res) => res.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Receive the response to `transaction`, skipping late responses to earlier attempts.
+    async fn recv(&self, transaction: u32) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let len = self
+                .socket
+                .recv(&mut buf)
+                .await
+                .context("receiving from tracker")?;
+            if len >= 8 && be_u32(&buf[4..]) == transaction {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    }
+
+    /// Turn an error response into an error, and make sure anything else is for `action`.
+    fn check(&self, res: Vec<u8>, action: u32) -> anyhow::Result<Vec<u8>> {
+        match be_u32(&res) {
+            ACTION_ERROR => {
+                // the connection id may be why the request failed
+                connection_ids().lock().unwrap().remove(&self.addr);
+                let reason = String::from_utf8_lossy(&res[8..]).into_owned();
+                Err(Failure { reason }.into())
+            }
+            a if a == action => Ok(res),
+            a => bail!("tracker responded with action {} to action {}", a, action),
+        }
+    }
+}
+
+fn next_retry(retries: &mut u32) -> anyhow::Result<()> {
+    ensure!(*retries < MAX_RETRIES, "tracker did not respond");
+    *retries += 1;
+    Ok(())
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes(buf[..4].try_into().unwrap())
+}
+
+fn be_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes(buf[..2].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1234_5678;
+
+    /// Info hashes the fake tracker answers scrapes for with an error
+    const UNKNOWN: [u8; 20] = [0xee; 20];
+
+    /// Answer requests the way a tracker would, until the test ends.
+    async fn fake_tracker() -> Url {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let packet = &buf[..len];
+                let (action, transaction) = (be_u32(&packet[8..]), &packet[12..16]);
+                let mut res = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(&packet[..8], PROTOCOL_ID.to_be_bytes());
+                        res.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        res.extend_from_slice(transaction);
+                        res.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(&packet[..8], CONNECTION_ID.to_be_bytes());
+                        assert_eq!(len, 98);
+                        assert_eq!(be_u16(&packet[96..]), 6881);
+                        res.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        res.extend_from_slice(transaction);
+                        for n in [1800u32, 3, 5] {
+                            res.extend_from_slice(&n.to_be_bytes());
+                        }
+                        res.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80]);
+                        // a truncated entry is ignored
+                        res.extend_from_slice(&[10, 0, 0]);
+                    }
+                    ACTION_SCRAPE if packet[16..].chunks(20).any(|h| h == UNKNOWN) => {
+                        res.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        res.extend_from_slice(transaction);
+                        res.extend_from_slice(b"unknown torrent");
+                    }
+                    ACTION_SCRAPE => {
+                        res.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        res.extend_from_slice(transaction);
+                        for hash in packet[16..].chunks(20) {
+                            for n in [hash[0], 7, 1] {
+                                res.extend_from_slice(&u32::from(n).to_be_bytes());
+                            }
+                        }
+                    }
+                    _ => panic!("unexpected action {}", action),
+                }
+                socket.send_to(&res, from).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn announce_responses_are_parsed() {
+        let url = fake_tracker().await;
+        let request = AnnounceRequest {
+            info_hash: [1; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: Some(Event::Started),
+            numwant: 50,
+            tracker_id: None,
+        };
+        let res = announce(&url, &request).await.unwrap();
+        assert_eq!(res.interval, Duration::from_secs(1800));
+        assert_eq!((res.leechers, res.seeders), (Some(3), Some(5)));
+        assert_eq!(
+            res.peers,
+            [
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:80".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_responses_are_parsed_in_order() {
+        let url = fake_tracker().await;
+        let stats = scrape(&url, &[[2; 20], [9; 20]]).await.unwrap();
+        let expected = |seeders| ScrapeStats {
+            seeders,
+            completed: 7,
+            leechers: 1,
+        };
+        assert_eq!(stats, [expected(2), expected(9)]);
+
+        let err = scrape(&url, &[UNKNOWN]).await.unwrap_err();
+        assert_eq!(err.downcast::<Failure>().unwrap().reason, "unknown torrent");
+    }
+}
@@ -0,0 +1,166 @@
+//! Checking data on disk against a torrent without going to the network.
+
+use std::{io::SeekFrom, path::Path};
+
+use anyhow::Context;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{storage::Layout, TorrentInfo};
+
+/// What rehashing found for a piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PieceStatus {
+    /// The piece matches its hash
+    Complete,
+    /// Some of the piece lies in a file which doesn't exist or is too short
+    Missing,
+    /// The data is all there but doesn't match the piece's hash
+    Corrupt,
+}
+
+/// The status of every piece of a torrent, by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub pieces: Vec<PieceStatus>,
+}
+
+impl Report {
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.pieces.iter().filter(|&&s| s == status).count()
+    }
+
+    /// Indices of the pieces with `status`, in order.
+    pub fn indices(&self, status: PieceStatus) -> impl Iterator<Item = u32> + '_ {
+        (0..)
+            .zip(&self.pieces)
+            .filter(move |(_, &s)| s == status)
+            .map(|(index, _)| index)
+    }
+
+    /// Share of the pieces which are complete, from 0 to 100.
+    pub fn percent_complete(&self) -> f64 {
+        if self.pieces.is_empty() {
+            return 100.0;
+        }
+        self.count(PieceStatus::Complete) as f64 * 100.0 / self.pieces.len() as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|&s| s == PieceStatus::Complete)
+    }
+}
+
+/// Rehash every piece of the torrent described by `info`, laid out under `root` as a download
+/// would be.
+///
+/// Nothing is created or modified on disk.
+pub async fn verify(info: &TorrentInfo, root: impl AsRef<Path>) -> anyhow::Result<Report> {
+    let layout = Layout::new(info, root)?;
+    // files which can't be opened are treated as empty, so pieces in them count as missing
+    let mut files = Vec::with_capacity(layout.files().len());
+    for entry in layout.files() {
+        let file = match File::open(&entry.path).await {
+            Ok(file) => {
+                let len = file
+                    .metadata()
+                    .await
+                    .with_context(|| format!("reading {}", entry.path.display()))?
+                    .len();
+                Some((file, len))
+            }
+            Err(_) => None,
+        };
+        files.push(file);
+    }
+
+    let mut pieces = Vec::with_capacity(layout.piece_count() as usize);
+    for index in 0..layout.piece_count() {
+        let mut piece = vec![0; layout.piece_len(index) as usize];
+        let mut status = PieceStatus::Complete;
+        for span in layout.spans(index, 0, piece.len()) {
+            let Some((file, len)) = &mut files[span.file] else {
+                status = PieceStatus::Missing;
+                break;
+            };
+            if *len < span.offset + (span.end - span.start) as u64 {
+                status = PieceStatus::Missing;
+                break;
+            }
+            file.seek(SeekFrom::Start(span.offset))
+                .await
+                .context("seeking in file")?;
+            file.read_exact(&mut piece[span.start..span.end])
+                .await
+                .context("reading from file")?;
+        }
+        if status == PieceStatus::Complete && !info.verify_piece(index, &piece) {
+            status = PieceStatus::Corrupt;
+        }
+        pieces.push(status);
+    }
+    Ok(Report { pieces })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        create::{create, CreateOptions},
+        Torrent,
+    };
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    #[tokio::test]
+    async fn created_torrents_verify_against_their_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), data(40000, 1)).unwrap();
+        std::fs::write(root.join("sub").join("b"), b"").unwrap();
+        std::fs::write(root.join("sub").join("c"), data(30000, 2)).unwrap();
+
+        let options = CreateOptions {
+            piece_length: Some(1 << 14),
+            ..Default::default()
+        };
+        let (info_hash, encoded) = create(&root, &options).await.unwrap();
+        let torrent_file = dir.path().join("shared.torrent");
+        std::fs::write(&torrent_file, encoded).unwrap();
+        let (read_hash, torrent) = Torrent::read_file(&torrent_file).await.unwrap();
+        assert_eq!(read_hash, info_hash);
+        assert_eq!(torrent.info.length(), 70000);
+
+        let report = verify(&torrent.info, &root).await.unwrap();
+        assert_eq!(report.pieces, [PieceStatus::Complete; 5]);
+        assert!(report.is_complete());
+
+        // flip a byte in the fourth piece, and cut the first file short
+        let mut c = data(30000, 2);
+        c[20000] ^= 0xff;
+        std::fs::write(root.join("sub").join("c"), c).unwrap();
+        std::fs::write(root.join("a"), &data(40000, 1)[..20000]).unwrap();
+        let report = verify(&torrent.info, &root).await.unwrap();
+        assert_eq!(
+            report.pieces,
+            [
+                PieceStatus::Complete,
+                PieceStatus::Missing,
+                PieceStatus::Missing,
+                PieceStatus::Corrupt,
+                PieceStatus::Complete,
+            ]
+        );
+        assert_eq!(report.percent_complete(), 40.0);
+
+        let report = verify(&torrent.info, dir.path().join("elsewhere"))
+            .await
+            .unwrap();
+        assert_eq!(report.count(PieceStatus::Missing), 5);
+    }
+}